@@ -1,14 +1,28 @@
-use std::{env, fs::{self, OpenOptions}, io::{self, BufReader, Read, Write}, net::TcpStream, path::{Path, PathBuf}, process::{Command, Stdio}};
+use std::{collections::{BTreeMap, HashSet, VecDeque}, env, fmt, fs, io::{Read, Write}, path::{Path, PathBuf}, process::{Command, Stdio}, sync::{mpsc, Mutex}, thread};
 use anyhow::anyhow;
 use crossterm::{cursor, terminal, ExecutableCommand};
 use ftp::FtpStream;
 use path_slash::{PathBufExt, PathExt};
 use serde::{Deserialize, Serialize};
-use ssh2::Session;
-use walkdir::WalkDir;
-use zip::ZipArchive;
 
-use crate::cli::Arguments;
+use crate::{backup::{self, BACKUP_DIR}, build::{self, Artifacts}, cli::{Arguments, Commands}, config::Config, debug, drift::{self, Expected}, error, export::{self, Content, Tree}, filter::{self, Filter}, git, history::{self, HISTORY_FILE}, hooks::{self, Context, Stage}, hosts, info, journal::Journal, lfs, lock::{Lock, LOCK_FILE}, log, manifest::{Manifest, MANIFEST_FILE}, permissions, report::{self, Failure}, secrets::{self, Secrets}, template, transport::{self, Connect, FtpTransport, SshTransport, Transport}, warn};
+
+const REVISION_FILE: &str = "revision.json";
+
+/// Exports built in this run by key of the server state they were built against, None when that state is up to date
+static EXPORTS: Mutex<BTreeMap<String, Option<Tree>>> = Mutex::new(BTreeMap::new());
+
+/// The server already has everything that would be deployed
+#[derive(Debug)]
+pub struct UpToDate(pub String);
+
+impl fmt::Display for UpToDate {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "Server is already at {}", self.0)
+	}
+}
+
+impl std::error::Error for UpToDate {}
 
 pub trait Executor {
 	fn execute(&self) -> Result<bool, anyhow::Error>;
@@ -18,7 +32,13 @@ pub trait Executor {
 pub struct Admin {
 	revision: String,
 	previous: String,
-	branch: String
+	branch: String,
+	#[serde(default, skip_serializing_if = "String::is_empty")]
+	build: String,
+	#[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+	templates: BTreeMap<String, String>,
+	#[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+	secrets: BTreeMap<String, String>
 }
 
 #[derive(Serialize, Deserialize, Debug, Default)]
@@ -32,7 +52,10 @@ impl Revision {
 		let admin = Admin {
 			revision: String::from(revision),
 			previous: String::from(revision),
-			branch: String::from(branch)
+			branch: String::from(branch),
+			build: String::new(),
+			templates: BTreeMap::new(),
+			secrets: BTreeMap::new()
 		};
 
 		Revision {
@@ -49,35 +72,17 @@ pub struct Export<'a> {
 
 impl<'a> Export<'a> {
 	
-	pub fn new(mut args: Arguments) -> Result<Self, anyhow::Error> {
-
-		let mut local_repo = args.local.clone();
+	pub fn new(args: Arguments) -> Result<Self, anyhow::Error> {
 
-		if local_repo.is_empty() {
-			local_repo = env::current_dir().unwrap().to_string_lossy().to_string()
-		}
-		else {
-			local_repo = shellexpand::full(&local_repo).unwrap().to_string();
-		}
-
-		args.local = local_repo;
-
-		is_git_repo(&args)?;
-
-		if args.dist {
-			args.create = true;
-		}
+		Self::open(prepare(args)?)
+	}
 
-		let connection_str = format!("{}:{}", args.host, "22");
+	/// Connect with arguments already passed through `prepare`
+	pub fn open(args: Arguments) -> Result<Self, anyhow::Error> {
 
-		let tcp = TcpStream::connect(connection_str)?;
+		check_target(&args)?;
 
-		let mut session = Session::new()?;
-		session.set_compress(true);
-		session.set_tcp_stream(tcp);
-	
-		session.handshake()?;
-		session.userauth_password(&args.user, &args.password)?;
+		let session = transport::ssh_session(&args)?;
 
 		Ok(Self {
 			session: session.clone(),
@@ -117,1125 +122,1517 @@ impl<'a> Export<'a> {
 
 	pub fn git_pull(&self) -> Result<(), anyhow::Error> {
 
-		let args = &self.args;
-
-		let local_path = PathBuf::from(&args.local);
-
-		println!();
-		println!("git pull");
-		println!();
-
-		let git_pull = Command::new("git")
-					.current_dir(&local_path)
-					.arg("pull")
-					.stdout(Stdio::inherit())
-					.stderr(Stdio::inherit())
-					.output().unwrap();
-
-		if !git_pull.status.success() {
-			return Err(anyhow!(String::from_utf8(git_pull.stderr)?));
-		}
-
-		Ok(())
+		git_pull(&self.args)
 	}
 
-	fn create_dist(&self) -> Result<PathBuf, anyhow::Error> {
+	pub fn deploy(&self) -> Result<bool, anyhow::Error> {
 
 		let args = &self.args;
-		let local_repo = args.local.clone();
-		let dest = args.destination.clone();
-		let verbose = args.verbose;
-
-		let pid = std::process::id();
-		let temp_dir = env::temp_dir().to_slash().unwrap().to_string();
 
-		let mut export_path = PathBuf::new();
+		let server = if args.dist || args.create {
+			None
+		}
+		else if args.manifest {
+			self.get_revision_file().ok()
+		}
+		else {
+			Some(self.get_revision_file()?)
+		};
 
-		env::set_current_dir(&local_repo).unwrap();
+		let mut transport = SshTransport::new(self.session.clone());
 
-		let dest_path = PathBuf::from(dest.clone());
+		let connect = || -> Result<Box<dyn Transport + Send>, anyhow::Error> {
+			Ok(Box::new(SshTransport::connect(args)?))
+		};
 
-		let dir_name = dest_path.file_name().unwrap();
+		deploy_tree(args, server, &mut transport, &connect)
+	}
 
-		let folder_name = dir_name.to_str().unwrap();
-		
-		let mut deploy_path = PathBuf::new();
+	pub fn drift(&self, download: Option<&str>) -> Result<bool, anyhow::Error> {
 
-		deploy_path.push(temp_dir.clone());
-		deploy_path.push(format!("deploy-{}", pid));
+		let server = self.get_revision_file()?;
 
-		let file_path = deploy_path.join(format!("{}.zip",folder_name));
+		let mut transport = SshTransport::new(self.session.clone());
 
-		if file_path.exists() {
+		check_drift(&self.args, &server, download, &mut transport)
+	}
 
-			fs::remove_file(file_path.clone()).unwrap();
-		}
+	pub fn history(&self, last: usize) -> Result<bool, anyhow::Error> {
 
-		if !deploy_path.exists(){
+		let mut transport = SshTransport::new(self.session.clone());
 
-			fs::create_dir_all(deploy_path.as_path()).unwrap();
-		}
+		show_history(&self.args, last, &mut transport)
+	}
 
-		let head_output = Command::new("git")
-			.arg("rev-parse")
-			.arg("HEAD")
-			.output().unwrap();
+	/// Deploy, or check for drift, every mapping without pulling first
+	fn run(&self) -> Result<bool, anyhow::Error> {
 
-		let head_ref = String::from_utf8(head_output.stdout).unwrap();
+		for_targets(&self.args, |args| {
 
-		let head_ref = head_ref.trim_end();
+			let export = Export {
+				session: self.session.clone(),
+				args,
+				files: vec![]
+			};
 
-		let cmd = format!("git archive -o {} HEAD", file_path.clone().to_str().unwrap());
+			match &export.args.command {
+				Some(Commands::Drift { download }) => export.drift(download.as_deref()),
+				Some(Commands::History { last }) => export.history(*last),
+				None => export.deploy()
+			}
+		})
+	}
+}
 
-		let cmd_output = if cfg!(target_os = "windows") {
+impl<'a> Executor for Export<'a> {
+	fn execute(&self) -> Result<bool, anyhow::Error> {
+		
+		self.git_pull()?;
 
-			Command::new("powershell")
-				.args(["-c", cmd.clone().as_str()])
-				.stdout(Stdio::inherit())
-				.output().unwrap()
-		} else {
-			Command::new("bash")
-					.args(["-c", cmd.clone().as_str()])
-					.stdout(Stdio::inherit())
-					.output().unwrap()
-		};
+		self.run()
+	}
+}
 
-		let mut success = cmd_output.status.success();
+/// Resolve the local repository and load the config and secrets
+pub fn prepare(mut args: Arguments) -> Result<Arguments, anyhow::Error> {
 
-		if success {
+	let mut local_repo = args.local.clone();
 
-			let zip_file = fs::File::open(file_path.clone()).unwrap();
+	if local_repo.is_empty() {
+		local_repo = env::current_dir().unwrap().to_string_lossy().to_string()
+	}
+	else {
+		local_repo = shellexpand::full(&local_repo).unwrap().to_string();
+	}
 
-			let mut archive = ZipArchive::new(zip_file).unwrap();
+	args.local = local_repo;
 
-			if verbose {
-				println!();
-				println!("Extracting {}", file_path.clone().to_str().unwrap());
-				println!();
-			}
+	is_git_repo(&args)?;
 
-			for i in 0..archive.len() {
+	args.settings = Config::load(&args)?;
+	args.vault = Secrets::load(&args)?;
 
-				let mut file = archive.by_index(i).unwrap();
+	if args.dist {
+		args.create = true;
+	}
 
-				let outpath = match file.enclosed_name() {
-					Some(path) => path.to_owned(),
-					None => continue,
-				};
+	Ok(args)
+}
 
-				let mut out_path = PathBuf::new();
-				out_path.push(deploy_path.clone());
-				out_path.push(folder_name);
-				out_path.push(outpath.clone());
-		
-				if (file.name()).ends_with('/') {
+fn check_target(args: &Arguments) -> Result<(), anyhow::Error> {
 
-					if verbose {
-						println!("Creating directory: {}", out_path.clone().to_str().unwrap());
-					}
+	if args.host.is_empty() || args.user.is_empty() || args.destination.is_empty() {
+		return Err(anyhow!("--host, --user and --dest are required unless the profile lists hosts"));
+	}
 
-					fs::create_dir_all(&out_path).unwrap();
-
-				} else {
-					if let Some(p) = out_path.parent() {
-						if !p.exists() {
-							
-							if verbose {
-								println!("Creating file: {}", file.name());
-							}
-								
-							fs::create_dir_all(p).unwrap();
-						}
-					}
-					let mut outfile = fs::File::create(&out_path).unwrap();
-					io::copy(&mut file, &mut outfile).unwrap();
-				}
-			}
+	Ok(())
+}
 
-			fs::remove_file(file_path).unwrap();
+fn git_pull(args: &Arguments) -> Result<(), anyhow::Error> {
 
-			export_path.push(deploy_path.clone());
-			export_path.push(folder_name);
+	let local_path = PathBuf::from(&args.local);
 
-			env::set_current_dir(&local_repo).unwrap();
+	info!();
+	info!("git pull");
+	info!();
 
-			let branch_output = Command::new("git")
-				.arg("rev-parse")
-				.arg("--abbrev-ref")
-				.arg("HEAD")
+	let git_pull = Command::new("git")
+				.current_dir(&local_path)
+				.arg("pull")
+				.stdout(log::child_output())
+				.stderr(Stdio::inherit())
 				.output().unwrap();
 
-			success = branch_output.status.success();
-
-			if success {
-				
-				env::set_current_dir(export_path.clone()).unwrap();
+	if !git_pull.status.success() {
+		return Err(Failure::Git.wrap(anyhow!(String::from_utf8(git_pull.stderr)?)));
+	}
 
-				let branch = String::from_utf8(branch_output.stdout).unwrap();
+	update_submodules(args)
+}
 
-				let branch = branch.trim_end();
+fn is_git_repo(args: &Arguments) -> Result<bool, anyhow::Error> {
 
-				if verbose {
-					println!();
-					println!("Creating revision.json");
-				}
-				
-				let revision = Revision::new(head_ref, branch);
+	let local_path = PathBuf::from(&args.local);
 
-				let revision_json = serde_json::to_string(&revision).unwrap();
+	let repo_output = Command::new("git")
+		.current_dir(&local_path)
+		.arg("rev-parse")
+		.arg("--is-inside-work-tree")
+		.output().unwrap();
 
-				let mut revision_path = export_path.clone();
+	let is_repo = String::from_utf8(repo_output.stdout).unwrap();
 
-				revision_path.push(deploy_path.clone());
-				revision_path.push(folder_name);
-				revision_path.push("revision.json");
+	let is_repo = is_repo.trim_end();
 
-				let mut revision_file = fs::OpenOptions::new().write(true).create_new(true).open(revision_path).unwrap();
+	if is_repo != "true" {
+		Err(Failure::Git.wrap(anyhow!("This can only be run in a git repo...")))
+	}
+	else {
+		Ok(true)
+	}
+}
 
-				revision_file.write_all(revision_json.as_bytes()).unwrap();
+fn update_submodules(args: &Arguments) -> Result<(), anyhow::Error> {
 
-				println!();
-				println!("BRANCH: {}", &branch);
-				println!("HEAD: {}", &head_ref);
-				println!("SERVER: {}", &head_ref);
-				println!("LOCAL: {}", export_path.clone().to_slash().unwrap());
-			}
-			else {
-				return Err(anyhow!("Could not create revision file"));
-			}
-			
-		}
-		else {
-			return Err(anyhow!("Could not create git archive"));
-		}
+	let local_path = PathBuf::from(&args.local);
 
-		Ok(export_path)
+	if !local_path.join(".gitmodules").exists() {
+		return Ok(());
 	}
 
-	fn create_export(&self) -> Result<PathBuf, anyhow::Error> {
+	info!();
+	info!("git submodule update");
+	info!();
 
-		let args = &self.args;
-		let local_repo = args.local.clone();
-		let dest = args.destination.clone();
-		let verbose = args.verbose;
-
-		let pid = std::process::id();
-		let temp_dir = env::temp_dir().to_slash().unwrap().to_string();
+	let git_update = Command::new("git")
+				.current_dir(&local_path)
+				.args(["submodule", "update", "--init", "--recursive"])
+				.stdout(log::child_output())
+				.stderr(Stdio::inherit())
+				.output().unwrap();
 
-		let mut export_path = PathBuf::new();
+	if !git_update.status.success() {
+		return Err(Failure::Git.wrap(anyhow!(String::from_utf8(git_update.stderr)?)));
+	}
 
-		let revision_file_server = self.get_revision_file()?;
+	Ok(())
+}
 
-		env::set_current_dir(&local_repo)?;
+fn deploy_path(args: &Arguments) -> PathBuf {
 
-		let dest_path = PathBuf::from(dest.clone());
+	let pid = std::process::id();
+	let temp_dir = env::temp_dir().to_slash().unwrap().to_string();
 
-		let dir_name = dest_path.file_name().unwrap();
+	let dest_path = PathBuf::from(args.destination.clone());
 
-		let folder_name = dir_name.to_str().unwrap();
-		
-		let mut deploy_path = PathBuf::new();
+	let folder_name = dest_path.file_name().unwrap().to_string_lossy().to_string();
 
-		deploy_path.push(temp_dir.clone());
-		deploy_path.push(format!("deploy-{}", pid));
+	let mut deploy_path = PathBuf::new();
 
-		let file_path = deploy_path.join(format!("{}.zip",folder_name));
+	deploy_path.push(temp_dir);
+	deploy_path.push(format!("deploy-{}", pid));
+	deploy_path.push(folder_name);
 
-		if file_path.exists() {
+	deploy_path
+}
 
-			fs::remove_file(file_path.clone())?;
-		}
+fn base_dest(args: &Arguments) -> String {
 
-		if !deploy_path.exists(){
+	let mut dest = args.destination.clone();
 
-			fs::create_dir_all(deploy_path.as_path()).unwrap();
-		}
+	if dest.starts_with('.') {
+		dest.remove(0);
+	}
 
-		let head_output = Command::new("git")
-			.arg("rev-parse")
-			.arg("HEAD")
-			.output().unwrap();
+	dest
+}
 
-		let head_ref = String::from_utf8(head_output.stdout.clone()).unwrap();
+fn remote_dest(args: &Arguments) -> PathBuf {
 
-		let head_ref = head_ref.trim_end();
-		
-		if head_ref != revision_file_server.admin.revision {
+	let mut dest_path = PathBuf::new();
 
-			let mut temp_list = PathBuf::new();
+	dest_path.push(base_dest(args));
 
-			temp_list.push(temp_dir);
-			temp_list.push("list.txt");
+	if args.dist {
+		let time_stamp = chrono::offset::Local::now().format("%Y%m%d-%H%M%S").to_string();
 
-			let temp_slashed = temp_list.to_slash().unwrap();
+		dest_path.push(time_stamp);
+	}
 
-			let cmd = format!("git diff --name-only --diff-filter=d {} HEAD > {}", revision_file_server.admin.revision, temp_slashed.clone());
+	dest_path
+}
 
-			let cmd_output = if cfg!(target_os = "windows") {
+fn add_revision(tree: &mut Tree, mut revision: Revision) -> Result<(), anyhow::Error> {
 
-				Command::new("cmd")
-					.args(["/C", cmd.clone().as_str()])
-					.stdout(Stdio::inherit())
-					.output().unwrap()
-			} else {
-				Command::new("sh")
-						.args(["-c", cmd.clone().as_str()])
-						.stdout(Stdio::inherit())
-						.output().unwrap()
-			};
+	debug!();
+	debug!("Creating revision.json");
 
-			let mut success = cmd_output.status.success();
+	revision.admin.templates = tree.templates.clone();
+	revision.admin.secrets = tree.secrets.clone();
 
-			if success && temp_list.exists() {
+	let revision_json = serde_json::to_string(&revision)?;
 
-				let list_data = fs::read_to_string(&temp_list).unwrap();
+	tree.add_file(REVISION_FILE, revision_json.into_bytes());
 
-				let lines = list_data.lines();
+	tree.head = revision.admin.revision.clone();
 
-				if verbose {
-					println!();
-					println!("Extracting {}", file_path.clone().to_str().unwrap());
-					println!();
-				}
-				
+	Ok(())
+}
 
-				for line in lines {
+fn add_artifacts(tree: &mut Tree, artifacts: &Artifacts, filter: &Filter) {
 
-					let mut out_path = PathBuf::new();
-					out_path.push(deploy_path.clone());
-					out_path.push(folder_name);
-					
-					let mut file_path = out_path.clone();
-					file_path.push(line);
+	for (path, data) in &artifacts.files {
 
-					let parent_dir = file_path.parent().unwrap();
-			
-					if !&parent_dir.exists() {
+		if filter.is_excluded(path) {
 
-						if verbose {
-							println!("Creating path: {}", parent_dir.to_string_lossy());
-						}
+			debug!("Excluding: {}", path);
 
-						fs::create_dir_all(parent_dir).unwrap();
-					}
+			continue;
+		}
 
-					out_path.push(line);
+		debug!("Adding build output: {}", path);
 
-					let mut outfile = fs::File::create(&out_path).unwrap();
+		tree.add_file(path, data.clone());
+	}
+}
 
-					let mut local_file_path = PathBuf::new();
-					local_file_path.push(&local_repo);
-					local_file_path.push(line);
+/// The part of `tree` that `--source` selects
+fn map_source(args: &Arguments, tree: Tree) -> Tree {
 
-					let mut file = fs::OpenOptions::new().read(true).open(local_file_path).unwrap();
-					io::copy(&mut file, &mut outfile).unwrap();
-				}
+	if args.source.is_empty() {
+		return tree;
+	}
 
-				if file_path.exists() {
+	tree.subtree(&args.source)
+}
 
-					fs::remove_file(file_path.clone())?;
-				}
+/// One set of arguments per configured mapping, or the given ones when there are no mappings or `--source` is set
+fn targets(args: &Arguments) -> Vec<Arguments> {
 
-				export_path.push(deploy_path.clone());
-				export_path.push(folder_name);
+	if !args.source.is_empty() || args.settings.mappings.is_empty() {
+		return vec![args.clone()];
+	}
 
-				env::set_current_dir(&local_repo).unwrap();
+	args.settings.mappings.iter()
+		.map(|mapping| {
 
-				let branch_output = Command::new("git")
-					.arg("rev-parse")
-					.arg("--abbrev-ref")
-					.arg("HEAD")
-					.output().unwrap();
+			let mut target = args.clone();
 
-				success = branch_output.status.success();
+			target.source = mapping.source.clone();
 
-				if success {
+			if !mapping.target.starts_with('/') {
+				target.destination = format!("{}/{}", args.destination.trim_end_matches('/'), mapping.target);
+			}
+			else {
+				target.destination = mapping.target.clone();
+			}
 
-					env::set_current_dir(export_path.clone()).unwrap();
+			target
+		})
+		.collect()
+}
 
-					let branch = String::from_utf8(branch_output.stdout).unwrap();
+/// Run `run` for every target, going on past targets that are already up to date when there are several
+fn for_targets<F>(args: &Arguments, mut run: F) -> Result<bool, anyhow::Error>
+	where F: FnMut(Arguments) -> Result<bool, anyhow::Error> {
 
-					let branch = branch.trim_end();
+	let targets = targets(args);
+	let several = targets.len() > 1;
 
-					if verbose {
-						println!();
-						println!("Creating revision.json");
-					}
+	let mut deployed = false;
 
-					let revision = Revision::new(head_ref, branch);
+	for target in targets {
 
-					let revision_json = serde_json::to_string(&revision).unwrap();
+		if several {
+			info!();
+			info!("MAPPING: {}/ -> {}", target.source.trim_matches('/'), target.destination);
+		}
 
-					let mut revision_path = export_path.clone();
+		match run(target) {
+			Ok(result) => deployed |= result,
+			Err(err) if several && err.is::<UpToDate>() => info!("{}", err),
+			Err(err) => return Err(err)
+		}
+	}
 
-					revision_path.push(deploy_path.clone());
-					revision_path.push(folder_name);
-					revision_path.push("revision.json");
+	Ok(deployed)
+}
 
-					if revision_path.exists() {
-						fs::remove_file(revision_path.clone()).unwrap();
-					}
+fn keep_export(args: &Arguments, tree: &Tree) -> Result<(), anyhow::Error> {
 
-					let mut revision_file = fs::OpenOptions::new().write(true).create_new(true).open(revision_path).unwrap();
+	if !args.temp_dir {
+		return Ok(());
+	}
 
-					revision_file.write_all(revision_json.as_bytes()).unwrap();
+	let export_path = deploy_path(args);
 
-					println!();
-					println!("BRANCH: {}", &branch);
-					println!("HEAD: {}", &head_ref);
-					println!("SERVER: {}", &revision_file_server.admin.revision);
-					println!("LOCAL: {}", export_path.clone().to_slash().unwrap());
+	if export_path.exists() {
+		fs::remove_dir_all(&export_path)?;
+	}
 
-				}
-			}
-			else {
-				return Err(anyhow!(format!("ERROR: {:?}", cmd_output)));
-			}
-		}
-		else {
-			return Err(anyhow!(format!("ERROR: {:?}", head_output)));
-		}
+	tree.write(&export_path)?;
 
-		Ok(export_path)
-	}
+	info!("LOCAL: {}", export_path.to_slash().unwrap());
 
-	#[warn(unused_assignments)]
-	pub fn deploy(&self) -> Result<bool, anyhow::Error> {
+	let secret_count = tree.entries.iter().filter(|entry| entry.secret).count();
 
-		let args = &self.args;
-		let mut dest = args.destination.clone();
-		let verbose = args.verbose;
-		let session = &self.session;
+	if secret_count > 0 {
+		info!("Left out {} files holding secrets", secret_count);
+	}
 
-		let mut stdout = std::io::stdout();
+	Ok(())
+}
 
-		let dist = args.dist;
-		let create = args.create;
+fn add_manifest(tree: &mut Tree, manifest: &Manifest) -> Result<(), anyhow::Error> {
 
-		if dest.starts_with('.') {
-			dest.remove(0);
-		}
+	debug!();
+	debug!("Creating manifest.json");
 
-		let local_path = if dist || create {
-			self.create_dist()?
-		}
-		else {
-			self.create_export()?
-		};
+	tree.add_file(MANIFEST_FILE, serde_json::to_vec(manifest)?);
 
-		let mut dest_path = PathBuf::new();
+	Ok(())
+}
 
-		dest_path.push(dest);
+/// Every file of `head_ref`, with build output, but without revision.json
+fn read_head(args: &Arguments, head_ref: &str) -> Result<(Tree, Option<Artifacts>), anyhow::Error> {
 
-		if dist {
-			let time_stamp = chrono::offset::Local::now().format("%Y%m%d-%H%M%S").to_string();
+	let mut tree = read_git(args, head_ref)?;
 
-			dest_path.push(time_stamp);
-		}
+	let artifacts = build::run(args)?;
 
-		let local_str = local_path.clone().to_string_lossy().to_string();
+	if let Some(artifacts) = &artifacts {
+		add_artifacts(&mut tree, artifacts, &Filter::new(args)?);
+	}
 
-		let mut count :u64 = 0;
-		let mut current: u64 = 0;
+	Ok((tree, artifacts))
+}
 
-		for entry in WalkDir::new(local_path.clone()).into_iter().filter_map(|e| e.ok()) {
+/// Every file git has for `head_ref`, submodules and LFS content included
+fn read_git(args: &Arguments, head_ref: &str) -> Result<Tree, anyhow::Error> {
 
-			let meta_data = entry.metadata().unwrap();
+	let local_repo = PathBuf::from(&args.local);
+	let mut archives = vec![(local_repo.clone(), String::new(), head_ref.to_string())];
 
-			if meta_data.is_file() {
-				count += 1;
-			}
-		}
+	for module in git::submodules(&local_repo, head_ref, "")? {
+		archives.push((module.repo, module.prefix, module.commit));
+	}
 
-		println!();
+	let filter = Filter::new(args)?;
 
-		let mut counter = 3;
+	let mut tree = Tree::new();
 
-		for entry in WalkDir::new(local_path.clone()).into_iter().filter_map(|e| e.ok()) {
+	debug!();
+	debug!("Reading git archive");
+	debug!();
 
-			let meta_data = entry.metadata().unwrap();
+	for (repo, prefix, commit) in &archives {
 
-			let dest_export = entry.path().to_path_buf();
+		export::read_archive(repo, commit, prefix, |path, content| {
 
-			let mut str_dest = String::from(dest_export.to_str().unwrap());
+			if filter.is_excluded(&path) {
 
-			str_dest = str_dest.replace(local_str.as_str(), dest_path.to_str().unwrap());
+				debug!("Excluding: {}", path);
 
-			let export_dest = str_dest.trim();
+				return Ok(());
+			}
 
-			let mut export_path = PathBuf::new();
+			debug!("Adding file: {}", path);
 
-			export_path.push(export_dest);
+			match content {
+				Content::File(data) => tree.add_file(&path, data),
+				Content::Symlink(target) => tree.add_symlink(&path, &target),
+				Content::Dir => {}
+			}
 
-			let str_export = export_path.to_slash().unwrap().to_string();
-			
-			if meta_data.is_dir() {
+			Ok(())
+		})?;
+	}
 
-				let mut cmd = Path::new(&str_export).to_slash_lossy().to_string();
+	lfs::resolve(&local_repo, head_ref, &mut tree)?;
 
-				cmd = format!("cd / && mkdir -p {}", cmd);
+	for path in git::executables(&local_repo, head_ref, "")? {
+		tree.set_executable(&path);
+	}
 
-				let mut channel = session.channel_session()?;
+	if args.dereference {
+		tree.dereference(&local_repo, None)?;
+	}
 
-				let result = channel.exec(&cmd);
+	template::render_tree(args, &mut tree)?;
 
-				match result {
-					Ok(_) => {
-						if verbose {
-							println!("MKDIR: {}", str_export.clone())
-						}
-					}
-					Err(error) => {
-						
-						if verbose {
-							println!("Error: {}", error);
-						}
-					} 
-				}
-			}
-			else if meta_data.is_file() {
+	secrets::add_files(args, &mut tree);
 
-				let entry_path = entry.path();
+	Ok(tree)
+}
 
-				let meta_data = entry.metadata().unwrap();
+/// Every template of `head_ref` rendered, and the secret files
+fn read_templates(args: &Arguments, head_ref: &str, filter: &Filter) -> Result<Tree, anyhow::Error> {
 
-				let file_name = entry_path.file_name().unwrap().to_string_lossy().to_string().replace('"', "");
+	let local_repo = PathBuf::from(&args.local);
 
-				let file_path = PathBuf::from(str_export);
+	let mut templates = Tree::new();
 
-				let dir_slashed = file_path.parent().unwrap().to_slash().unwrap().to_string();
+	secrets::add_files(args, &mut templates);
 
-				let mut file_path = PathBuf::new();
+	let paths: Vec<String> = git::all_files(&local_repo, head_ref, "")?.into_iter()
+		.filter(|path| template::output_path(path).is_some() && args.source_path(path).is_some())
+		.collect();
 
-				file_path.push(&dir_slashed);
-				file_path.push(&file_name);
+	if paths.is_empty() {
+		return Ok(templates);
+	}
 
-				if current > 0 {
+	let ignored = filter::export_ignored(&local_repo, head_ref, &paths)?;
 
-					let _ = stdout.execute(cursor::MoveUp(counter));
-					let _ = stdout.execute(terminal::Clear(terminal::ClearType::FromCursorDown));
-					counter = 3;
-				}
-				
-				current += 1;
+	for path in paths {
 
-				let progress = ((current as f64 / count as f64) * 100.0) as i32;
+		if !ignored.contains(&path) && !filter.is_excluded(&path) {
+			templates.add_file(&path, fs::read(local_repo.join(&path))?);
+		}
+	}
 
-				writeln!(stdout, "Deploying: {progress}%").unwrap();
-				writeln!(stdout, "{file_name}").unwrap();
-				writeln!(stdout, "{current} / {count}").unwrap();
+	for path in git::executables(&local_repo, head_ref, "")? {
+		templates.set_executable(&path);
+	}
 
-				let mut scp = session.scp_send(&file_path, 0o751, meta_data.len(), None)?;
+	template::render_tree(args, &mut templates)?;
 
-				let file = fs::OpenOptions::new().read(true).open(entry_path).unwrap();
+	Ok(templates)
+}
 
-				let mut reader = BufReader::new(file);
+fn create_dist(args: &Arguments) -> Result<Tree, anyhow::Error> {
 
-				let mut handle = [0; 4096];
+	let local_repo = PathBuf::from(&args.local);
+	let head_ref = git::head_ref(&local_repo)?;
 
-				loop {
-					
-					let len = reader.read(&mut handle).unwrap();
+	let (tree, artifacts) = read_head(args, &head_ref)?;
 
-					if len == 0  {
-						break;
-					}
+	let mut tree = map_source(args, tree);
 
-					let _ = scp.write_all(&handle[..len]);
+	let Ok(branch) = git::branch(&local_repo) else {
+		return Err(anyhow!("Could not create revision file"));
+	};
 
-				}
-				
-			}
-		}
+	let mut revision = Revision::new(&head_ref, &branch);
 
-		Ok(true)
+	if let Some(artifacts) = artifacts {
+		revision.admin.build = artifacts.hash;
 	}
-}
 
-impl<'a> Executor for Export<'a> {
-	fn execute(&self) -> Result<bool, anyhow::Error> {
-		
-		self.git_pull()?;
+	if args.manifest {
+		let manifest = Manifest::from_tree(&tree, &[REVISION_FILE]);
 
-		self.deploy()
+		add_manifest(&mut tree, &manifest)?;
 	}
-}
 
-fn is_git_repo(args: &Arguments) -> Result<bool, anyhow::Error> {
+	add_revision(&mut tree, revision)?;
 
-	let local_path = PathBuf::from(&args.local);
+	info!();
+	info!("BRANCH: {}", &branch);
+	info!("HEAD: {}", &head_ref);
+	info!("SERVER: {}", &head_ref);
 
-	let repo_output = Command::new("git")
-		.current_dir(&local_path)
-		.arg("rev-parse")
-		.arg("--is-inside-work-tree")
-		.output().unwrap();
+	keep_export(args, &tree)?;
 
-	let is_repo = String::from_utf8(repo_output.stdout).unwrap();
+	Ok(tree)
+}
 
-	let is_repo = is_repo.trim_end();
+/// Only the files whose hash differs from the manifest on the server, whatever the git history between them
+fn create_manifest_export(args: &Arguments, revision_file_server: Option<&Revision>, transport: &mut dyn Transport) -> Result<Tree, anyhow::Error> {
 
-	if is_repo != "true" {
-		Err(anyhow!("This can only be run in a git repo..."))
-	}
-	else {
-		Ok(true)
-	}
-}
+	let local_repo = PathBuf::from(&args.local);
+	let head_ref = git::head_ref(&local_repo)?;
 
-pub struct FtpExport<'a> {
-	pub args: Arguments,
-	pub files: Vec<&'a str>
-}
+	let (full, artifacts) = read_head(args, &head_ref)?;
 
-impl<'a> FtpExport<'a> {
+	let full = map_source(args, full);
 
-	pub fn new(mut args: Arguments) -> Result<Self, anyhow::Error> {
+	let manifest = Manifest::from_tree(&full, &[REVISION_FILE]);
 
-		let mut local_repo = args.local.clone();
+	let server_manifest = match Manifest::load(transport, &base_dest(args)) {
+		Some(server_manifest) => server_manifest,
+		None => {
+			info!();
+			info!("No manifest on the server, uploading all files");
 
-		if local_repo.is_empty() {
-			local_repo = env::current_dir().unwrap().to_string_lossy().to_string()
-		}
-		else {
-			local_repo = shellexpand::full(&local_repo).unwrap().to_string();
+			Manifest::default()
 		}
+	};
 
-		args.local = local_repo;
-
-		is_git_repo(&args)?;
+	let (changed, removed) = manifest.diff(&server_manifest);
 
-		if args.dist {
-			args.create = true;
-		}
+	let server_head = revision_file_server.map(|server| server.admin.revision.clone()).unwrap_or_default();
 
-		Ok(Self {
-			args: args.clone(),
-			files: vec![]
-		})
+	if changed.is_empty() && removed.is_empty() && server_head == head_ref {
+		return Err(UpToDate(head_ref).into());
 	}
 
-	fn git_pull(&self) -> Result<(), anyhow::Error> {
-
-		let args = &self.args;
+	let filter = Filter::new(args)?;
 
-		let local_path = PathBuf::from(&args.local);
+	let mut tree = Tree::new();
 
-		println!();
-		println!("git pull");
-		println!();
+	let changed_paths: HashSet<&str> = changed.iter().map(String::as_str).collect();
 
-		let git_pull = Command::new("git")
-					.current_dir(&local_path)
-					.arg("pull")
-					.stdout(Stdio::inherit())
-					.stderr(Stdio::inherit())
-					.output().unwrap();
+	for entry in &full.entries {
 
-		if !git_pull.status.success() {
-			return Err(anyhow!(String::from_utf8(git_pull.stderr)?));
+		if !changed_paths.contains(entry.path.as_str()) {
+			continue;
 		}
 
-		Ok(())
-	}
-
-	fn get_revision_file(&self) -> Result<Revision, anyhow::Error> {
-		
-		let mut folder = self.args.destination.clone();
+		match &entry.content {
+			Content::File(data) => tree.add_file(&entry.path, data.clone()),
+			Content::Symlink(target) => tree.add_symlink(&entry.path, target),
+			Content::Dir => {}
+		}
 
-		if folder.starts_with('.') {
-			let mut str = folder.to_string();
-			str.remove(0);
-			folder = str;
+		if entry.executable {
+			tree.set_executable(&entry.path);
 		}
 
-		let connection_str = format!("{}:{}", self.args.host, "21");
-		let mut ftp = FtpStream::connect(connection_str).unwrap();
+		if entry.secret {
+			tree.set_secret(&entry.path);
+		}
+	}
 
-		let _ = ftp.login(&self.args.user, &self.args.password);
+	tree.templates = full.templates.clone();
+	tree.secrets = full.secrets.clone();
 
-		let file = format!("{}/revision.json", folder);
+	// Files excluded now are left alone rather than deleted
+	for path in removed {
 
-		let mut recived = ftp.get(&file)?;
+		if filter.is_excluded(&args.repo_path(&path)) {
+			continue;
+		}
 
-		let mut content = String::from("");
+		debug!("Removing file: {}", path);
 
-		let _ = recived.read_to_string(&mut content);
+		tree.removed.push(path);
+	}
 
-		let revision = serde_json::from_str::<Revision>(&content)?;
+	let branch = git::branch(&local_repo)?;
 
-		let _ = ftp.quit();
+	let mut revision = Revision::new(&head_ref, &branch);
 
-		Ok(revision)
+	if let Some(artifacts) = artifacts {
+		revision.admin.build = artifacts.hash;
 	}
 
-	fn create_dist(&self) -> Result<PathBuf, anyhow::Error> {
+	add_manifest(&mut tree, &manifest)?;
+	add_revision(&mut tree, revision)?;
 
-		let args = &self.args;
-		let local_repo = args.local.clone();
-		let dest = args.destination.clone();
-		let verbose = args.verbose;
+	info!();
+	info!("BRANCH: {}", &branch);
+	info!("HEAD: {}", &head_ref);
+	info!("SERVER: {}", &server_head);
+	info!("CHANGED: {}, REMOVED: {}", changed.len(), tree.removed.len());
 
-		let pid = std::process::id();
-		let temp_dir = env::temp_dir().to_slash().unwrap().to_string();
+	keep_export(args, &tree)?;
 
-		let mut export_path = PathBuf::new();
+	Ok(tree)
+}
 
-		env::set_current_dir(&local_repo).unwrap();
+/// Every file the symlinks touched by `changed` point to, read in full from `rev` since `changed` only holds part of them
+///
+/// Links that are unchanged themselves but whose target changed are added to `tree`, so their copies on the server follow the target.
+fn link_targets(local_repo: &Path, rev: &str, changed: &[String], filter: &Filter, tree: &mut Tree) -> Result<Tree, anyhow::Error> {
 
-		let dest_path = PathBuf::from(dest.clone());
+	let mut resolved_targets = vec![];
 
-		let dir_name = dest_path.file_name().unwrap();
+	for link in git::symlinks(local_repo, rev, "")? {
 
-		let folder_name = dir_name.to_str().unwrap();
-		
-		let mut deploy_path = PathBuf::new();
+		if filter.is_excluded(&link) {
+			continue;
+		}
 
-		deploy_path.push(temp_dir.clone());
-		deploy_path.push(format!("deploy-{}", pid));
+		let target = fs::read_link(local_repo.join(&link))?.to_slash_lossy().to_string();
 
-		let file_path = deploy_path.join(format!("{}.zip",folder_name));
+		// Links leaving the export fail in dereference when they are deployed
+		let Some(resolved) = export::resolve_link(&link, &target) else {
+			continue;
+		};
 
-		if file_path.exists() {
+		let dir = format!("{}/", resolved);
 
-			fs::remove_file(file_path.clone()).unwrap();
+		if !changed.iter().any(|path| *path == link || *path == resolved || path.starts_with(&dir)) {
+			continue;
 		}
 
-		if !deploy_path.exists(){
+		if !tree.symlinks().any(|(path, _)| path == link) {
+
+			debug!("Adding symlink with a changed target: {}", link);
 
-			fs::create_dir_all(deploy_path.as_path()).unwrap();
+			tree.add_symlink(&link, &target);
 		}
 
-		let head_output = Command::new("git")
-			.arg("rev-parse")
-			.arg("HEAD")
-			.output().unwrap();
+		resolved_targets.push(resolved);
+	}
 
-		let head_ref = String::from_utf8(head_output.stdout).unwrap();
+	let mut targets = Tree::new();
 
-		let head_ref = head_ref.trim_end();
+	if resolved_targets.is_empty() {
+		return Ok(targets);
+	}
 
-		let cmd = format!("git archive -o {} HEAD", file_path.clone().to_str().unwrap());
+	let paths: Vec<String> = git::all_files(local_repo, rev, "")?.into_iter()
+		.filter(|path| resolved_targets.iter().any(|target| path == target || path.starts_with(&format!("{}/", target))))
+		.filter(|path| !filter.is_excluded(path))
+		.collect();
 
-		let cmd_output = if cfg!(target_os = "windows") {
+	let ignored = filter::export_ignored(local_repo, rev, &paths)?;
 
-			Command::new("powershell")
-				.args(["-c", cmd.clone().as_str()])
-				.stdout(Stdio::inherit())
-				.output().unwrap()
-		} else {
-			Command::new("bash")
-					.args(["-c", cmd.clone().as_str()])
-					.stdout(Stdio::inherit())
-					.output().unwrap()
-		};
+	for path in paths {
 
-		let mut success = cmd_output.status.success();
+		let file_path = local_repo.join(&path);
 
-		if success {
+		// Links inside a linked directory are not followed any further
+		if ignored.contains(&path) || fs::symlink_metadata(&file_path)?.file_type().is_symlink() {
+			continue;
+		}
 
-			let zip_file = fs::File::open(file_path.clone()).unwrap();
+		targets.add_file(&path, fs::read(&file_path)?);
+	}
 
-			let mut archive = ZipArchive::new(zip_file).unwrap();
+	lfs::resolve(local_repo, rev, &mut targets)?;
 
-			if verbose {
-				println!();
-				println!("Extracting {}", file_path.clone().to_str().unwrap());
-				println!();
-			}
+	for path in git::executables(local_repo, rev, "")? {
+		targets.set_executable(&path);
+	}
 
-			for i in 0..archive.len() {
+	Ok(targets)
+}
 
-				let mut file = archive.by_index(i).unwrap();
+fn create_export(args: &Arguments, revision_file_server: &Revision) -> Result<Tree, anyhow::Error> {
 
-				let outpath = match file.enclosed_name() {
-					Some(path) => path.to_owned(),
-					None => continue,
-				};
+	let local_repo = PathBuf::from(&args.local);
+	let head_ref = git::head_ref(&local_repo)?;
 
-				let mut out_path = PathBuf::new();
-				out_path.push(deploy_path.clone());
-				out_path.push(folder_name);
-				out_path.push(outpath.clone());
-		
-				if (file.name()).ends_with('/') {
+	let artifacts = build::run(args)?;
 
-					if verbose {
-						println!("Creating directory: {}", out_path.clone().to_str().unwrap());
-					}
+	let build_changed = artifacts.as_ref()
+		.is_some_and(|artifacts| artifacts.hash != revision_file_server.admin.build);
 
-					fs::create_dir_all(&out_path).unwrap();
-
-				} else {
-					if let Some(p) = out_path.parent() {
-						if !p.exists() {
-							
-							if verbose {
-								println!("Creating file: {}", file.name());
-							}
-								
-							fs::create_dir_all(p).unwrap();
-						}
-					}
-					let mut outfile = fs::File::create(&out_path).unwrap();
-					io::copy(&mut file, &mut outfile).unwrap();
-				}
-			}
+	let filter = Filter::new(args)?;
 
-			fs::remove_file(file_path).unwrap();
+	// Templates are rendered every time, a change of variables changes the output as much as a commit
+	let templates = map_source(args, read_templates(args, &head_ref, &filter)?);
 
-			export_path.push(deploy_path.clone());
-			export_path.push(folder_name);
+	let server_admin = &revision_file_server.admin;
 
-			env::set_current_dir(&local_repo).unwrap();
+	let templates_changed: HashSet<&str> = templates.templates.iter()
+		.filter(|(path, hash)| server_admin.templates.get(*path) != Some(*hash))
+		.chain(templates.secrets.iter().filter(|(path, hash)| server_admin.secrets.get(*path) != Some(*hash)))
+		.map(|(path, _)| path.as_str())
+		.collect();
 
-			let branch_output = Command::new("git")
-				.arg("rev-parse")
-				.arg("--abbrev-ref")
-				.arg("HEAD")
-				.output().unwrap();
+	if head_ref == revision_file_server.admin.revision && !build_changed && templates_changed.is_empty() {
+		return Err(UpToDate(head_ref).into());
+	}
 
-			success = branch_output.status.success();
+	let lines = if head_ref == revision_file_server.admin.revision {
+		vec![]
+	}
+	else {
+		git::changed_files(&local_repo, &revision_file_server.admin.revision, &head_ref, "")?
+	};
 
-			if success {
-				
-				env::set_current_dir(export_path.clone()).unwrap();
+	let ignored = filter::export_ignored(&local_repo, &head_ref, &lines)?;
 
-				let branch = String::from_utf8(branch_output.stdout).unwrap();
+	let mut tree = Tree::new();
 
-				let branch = branch.trim_end();
+	debug!();
+	debug!("Reading changed files");
+	debug!();
 
-				if verbose {
-					println!();
-					println!("Creating revision.json");
-				}
-				
-				let revision = Revision::new(head_ref, branch);
+	for line in &lines {
+
+		let rendered = args.source_path(line).is_some_and(|target| templates.files().any(|(path, _)| path == target));
 
-				let revision_json = serde_json::to_string(&revision).unwrap();
+		if template::output_path(line).is_some() || rendered {
+			continue;
+		}
 
-				let mut revision_path = export_path.clone();
+		if ignored.contains(line) || filter.is_excluded(line) {
 
-				revision_path.push(deploy_path.clone());
-				revision_path.push(folder_name);
-				revision_path.push("revision.json");
+			debug!("Excluding: {}", line);
 
-				let mut revision_file = fs::OpenOptions::new().write(true).create_new(true).open(revision_path).unwrap();
+			continue;
+		}
 
-				revision_file.write_all(revision_json.as_bytes()).unwrap();
+		debug!("Adding file: {}", line);
 
-				println!();
-				println!("BRANCH: {}", &branch);
-				println!("HEAD: {}", &head_ref);
-				println!("SERVER: {}", &head_ref);
-				println!("LOCAL: {}", export_path.clone().to_slash().unwrap());
-			}
-			else {
-				return Err(anyhow!("Could not create revision file"));
-			}
-			
+		let file_path = local_repo.join(line);
+
+		if fs::symlink_metadata(&file_path)?.file_type().is_symlink() {
+			tree.add_symlink(line, &fs::read_link(&file_path)?.to_slash_lossy());
 		}
 		else {
-			return Err(anyhow!("Could not create git archive"));
+			tree.add_file(line, fs::read(&file_path)?);
 		}
+	}
+
+	lfs::resolve(&local_repo, &head_ref, &mut tree)?;
 
-		Ok(export_path)
+	for path in git::executables(&local_repo, &head_ref, "")? {
+		tree.set_executable(&path);
 	}
 
-	fn create_export(&self) -> Result<PathBuf, anyhow::Error> {
+	if args.dereference {
 
-		let args = &self.args;
-		let local_repo = args.local.clone();
-		let dest = args.destination.clone();
-		let verbose = args.verbose;
+		let targets = link_targets(&local_repo, &head_ref, &lines, &filter, &mut tree)?;
+
+		tree.dereference(&local_repo, Some(&targets))?;
+	}
+
+	if let Some(artifacts) = &artifacts {
 
-		let pid = std::process::id();
-		let temp_dir = env::temp_dir().to_slash().unwrap().to_string();
+		if build_changed {
+			add_artifacts(&mut tree, artifacts, &filter);
+		}
+		else {
+			debug!("Build output unchanged");
+		}
+	}
 
-		let mut export_path = PathBuf::new();
+	let mut tree = map_source(args, tree);
 
-		let revision_file_server = self.get_revision_file()?;
+	for entry in &templates.entries {
 
-		env::set_current_dir(&local_repo)?;
+		let Content::File(data) = &entry.content else {
+			continue;
+		};
 
-		let dest_path = PathBuf::from(dest.clone());
+		if templates_changed.contains(entry.path.as_str()) {
 
-		let dir_name = dest_path.file_name().unwrap();
+			tree.add_file(&entry.path, data.clone());
 
-		let folder_name = dir_name.to_str().unwrap();
-		
-		let mut deploy_path = PathBuf::new();
+			if entry.executable {
+				tree.set_executable(&entry.path);
+			}
+
+			if entry.secret {
+				tree.set_secret(&entry.path);
+			}
+		}
+	}
+
+	tree.templates = templates.templates.clone();
+	tree.secrets = templates.secrets.clone();
 
-		deploy_path.push(temp_dir.clone());
-		deploy_path.push(format!("deploy-{}", pid));
+	let branch = git::branch(&local_repo)?;
 
-		let file_path = deploy_path.join(format!("{}.zip",folder_name));
+	let mut revision = Revision::new(&head_ref, &branch);
 
-		if file_path.exists() {
+	if let Some(artifacts) = artifacts {
+		revision.admin.build = artifacts.hash;
+	}
+
+	add_revision(&mut tree, revision)?;
+
+	info!();
+	info!("BRANCH: {}", &branch);
+	info!("HEAD: {}", &head_ref);
+	info!("SERVER: {}", &revision_file_server.admin.revision);
+
+	keep_export(args, &tree)?;
+
+	Ok(tree)
+}
+
+/// Files the tool itself keeps in the destination
+fn is_deploy_file(path: &str) -> bool {
+
+	path.starts_with(&format!("{}/", BACKUP_DIR)) || [REVISION_FILE, MANIFEST_FILE, LOCK_FILE, HISTORY_FILE].iter()
+		.any(|name| path == *name || path.starts_with(&format!("{}.tmp-", name)))
+}
+
+/// Compare the destination with what the revision on the server should contain, trusting its manifest when there is one
+fn check_drift(args: &Arguments, server: &Revision, download: Option<&str>, transport: &mut dyn Transport) -> Result<bool, anyhow::Error> {
+
+	if args.dist {
+		return Err(anyhow!("Drift detection does not apply to --dist deployments"));
+	}
 
-			fs::remove_file(file_path.clone())?;
+	let dest = base_dest(args);
+
+	let revision = &server.admin.revision;
+
+	info!();
+	info!("SERVER: {}", revision);
+
+	let tree = map_source(args, read_git(args, revision)?);
+
+	let mut expected: BTreeMap<String, Expected> = tree.files()
+		.map(|(path, data)| (path.to_string(), Expected { size: Some(data.len() as u64), hash: export::sha256(data) }))
+		.collect();
+
+	let links: HashSet<&str> = tree.symlinks().map(|(path, _)| path).collect();
+
+	if let Some(manifest) = Manifest::load(transport, &dest) {
+
+		// Listings only cover regular files, symlinks are left out of the comparison
+		expected = manifest.files.into_iter()
+			.filter(|(path, _)| !links.contains(path.as_str()))
+			.map(|(path, hash)| {
+
+				let size = expected.get(&path)
+					.filter(|wanted| wanted.hash == hash)
+					.and_then(|wanted| wanted.size);
+
+				(path, Expected { size, hash })
+			})
+			.collect();
+	}
+
+	let remote = transport.list_files(&dest)?;
+
+	let filter = Filter::new(args)?;
+
+	let report = drift::compare(&expected, &remote, |path| is_deploy_file(path) || filter.is_excluded(&args.repo_path(path)));
+
+	report.print();
+
+	let mut target = report::Target::new(&args.host, &dest, revision, revision);
+
+	target.status = if report.is_clean() { report::Status::Clean } else { report::Status::Drift };
+
+	target.finish(&Ok(report.is_clean()));
+
+	if let Some(dir) = download {
+		drift::download(&report.modified, &dest, Path::new(dir), transport)?;
+	}
+
+	Ok(report.is_clean())
+}
+
+fn show_history(args: &Arguments, last: usize, transport: &mut dyn Transport) -> Result<bool, anyhow::Error> {
+
+	let entries = history::load(transport, &base_dest(args));
+
+	history::print(&entries, last, Path::new(&args.local));
+
+	Ok(true)
+}
+
+struct Progress {
+	stdout: Box<dyn Write + Send>,
+	count: u64,
+	current: u64,
+	/// Host and destination to log progress lines for, rather than redrawing them, when hosts deploy in parallel
+	host: Option<String>
+}
+
+impl Progress {
+
+	fn new(args: &Arguments, count: u64) -> Self {
+
+		Self {
+			stdout: log::console(),
+			count,
+			current: 0,
+			host: args.parallel_hosts.then(|| format!("{} {}", args.host, args.destination))
 		}
+	}
+
+	fn step(&mut self, file_name: &str) {
 
-		if !deploy_path.exists(){
+		if let Some(host) = &self.host {
 
-			fs::create_dir_all(deploy_path.as_path()).unwrap();
+			self.current += 1;
+
+			let progress = self.current * 100 / self.count;
+
+			// Steps of ten percent, since the lines of all hosts interleave
+			if progress / 10 > (self.current - 1) * 100 / self.count / 10 {
+				info!("{}: Deploying {}% ({} / {})", host, progress, self.current, self.count);
+			}
+
+			return;
 		}
 
-		let head_output = Command::new("git")
-			.arg("rev-parse")
-			.arg("HEAD")
-			.output().unwrap();
+		let stdout = &mut self.stdout;
 
-		let head_ref = String::from_utf8(head_output.stdout.clone()).unwrap();
+		if self.current > 0 {
 
-		let head_ref = head_ref.trim_end();
+			let _ = stdout.execute(cursor::MoveUp(3));
+			let _ = stdout.execute(terminal::Clear(terminal::ClearType::FromCursorDown));
+		}
 		
-		if head_ref != revision_file_server.admin.revision {
+		self.current += 1;
 
-			let mut temp_list = PathBuf::new();
+		let current = self.current;
+		let count = self.count;
 
-			temp_list.push(temp_dir);
-			temp_list.push("list.txt");
+		let progress = ((current as f64 / count as f64) * 100.0) as i32;
 
-			let temp_slashed = temp_list.to_slash().unwrap();
+		writeln!(stdout, "Deploying: {progress}%").unwrap();
+		writeln!(stdout, "{file_name}").unwrap();
+		writeln!(stdout, "{current} / {count}").unwrap();
+	}
+}
 
-			let cmd = format!("git diff --name-only --diff-filter=d {} HEAD > {}", revision_file_server.admin.revision, temp_slashed.clone());
+fn file_name(path: &str) -> String {
 
-			let cmd_output = if cfg!(target_os = "windows") {
+	path.rsplit('/').next().unwrap_or(path).replace('"', "")
+}
 
-				Command::new("cmd")
-					.args(["/C", cmd.clone().as_str()])
-					.stdout(Stdio::inherit())
-					.output().unwrap()
-			} else {
-				Command::new("sh")
-						.args(["-c", cmd.clone().as_str()])
-						.stdout(Stdio::inherit())
-						.output().unwrap()
-			};
+fn deploy_tree(args: &Arguments, server: Option<Revision>, transport: &mut dyn Transport, connect: &Connect) -> Result<bool, anyhow::Error> {
 
-			let mut success = cmd_output.status.success();
+	let local_repo = PathBuf::from(&args.local);
 
-			if success && temp_list.exists() {
+	let mut context = Context {
+		revision: git::head_ref(&local_repo)?,
+		previous: server.as_ref().map(|server| server.admin.revision.clone()).unwrap_or_default(),
+		branch: git::branch(&local_repo)?,
+		host: args.host.clone(),
+		destination: base_dest(args),
+		..Default::default()
+	};
 
-				let list_data = fs::read_to_string(&temp_list).unwrap();
+	let mut target = report::Target::new(&context.host, &context.destination, &context.previous, &context.revision);
 
-				let lines = list_data.lines();
+	let result = deploy_stages(args, server.as_ref(), &mut context, &mut target, transport, connect);
 
-				if verbose {
-					println!();
-					println!("Extracting {}", file_path.clone().to_str().unwrap());
-					println!();
-				}
-				
+	target.finish(&result);
+
+	// A server already up to date is not a failure worth alerting or rolling back for
+	if let Some(err) = result.as_ref().err().filter(|err| !err.is::<UpToDate>()) {
+
+		context.error = secrets::mask(&err.to_string());
+
+		if let Err(hook_err) = hooks::run_local(args, Stage::OnFailure, &context) {
+			error!("Err: {}", hook_err);
+		}
+	}
 
-				for line in lines {
+	result
+}
 
-					let mut out_path = PathBuf::new();
-					out_path.push(deploy_path.clone());
-					out_path.push(folder_name);
-					
-					let mut file_path = out_path.clone();
-					file_path.push(line);
+/// Build the export for `server`, only once per server revision when deploying to several hosts
+fn shared_export(args: &Arguments, server: Option<&Revision>) -> Result<Tree, anyhow::Error> {
 
-					let parent_dir = file_path.parent().unwrap();
-			
-					if !&parent_dir.exists() {
+	let create = || match server {
+		None => create_dist(args),
+		Some(server) => create_export(args, server)
+	};
 
-						if verbose {
-							println!("Creating path: {}", parent_dir.to_string_lossy());
-						}
+	if !hosts::configured(args) {
+		return create();
+	}
 
-						fs::create_dir_all(parent_dir).unwrap();
-					}
+	let admin = match server {
+		Some(server) => serde_json::to_string(&server.admin)?,
+		None => String::new()
+	};
 
-					out_path.push(line);
+	let key = format!("{}\n{}", args.source, admin);
 
-					let mut outfile = fs::File::create(&out_path).unwrap();
+	let mut exports = EXPORTS.lock().unwrap();
 
-					let mut local_file_path = PathBuf::new();
-					local_file_path.push(&local_repo);
-					local_file_path.push(line);
+	if let Some(export) = exports.get(&key) {
 
-					let mut file = fs::OpenOptions::new().read(true).open(local_file_path).unwrap();
-					io::copy(&mut file, &mut outfile).unwrap();
-				}
+		info!();
+		info!("Reusing the export built for another host at the same revision");
 
-				if file_path.exists() {
+		return match export {
+			Some(tree) => Ok(tree.clone()),
+			None => Err(UpToDate(git::head_ref(&PathBuf::from(&args.local))?).into())
+		};
+	}
 
-					fs::remove_file(file_path.clone())?;
-				}
+	match create() {
+		Ok(tree) => {
+			exports.insert(key, Some(tree.clone()));
+			Ok(tree)
+		},
+		Err(err) if err.is::<UpToDate>() => {
+			exports.insert(key, None);
+			Err(err)
+		},
+		Err(err) => Err(err)
+	}
+}
+
+fn deploy_stages(args: &Arguments, server: Option<&Revision>, context: &mut Context, target: &mut report::Target, transport: &mut dyn Transport, connect: &Connect) -> Result<bool, anyhow::Error> {
 
-				export_path.push(deploy_path.clone());
-				export_path.push(folder_name);
+	hooks::check_remote(args, transport)?;
+	permissions::check(args, transport)?;
 
-				env::set_current_dir(&local_repo).unwrap();
+	hooks::run_local(args, Stage::BeforeExport, context)?;
 
-				let branch_output = Command::new("git")
-					.arg("rev-parse")
-					.arg("--abbrev-ref")
-					.arg("HEAD")
-					.output().unwrap();
+	let tree = if args.manifest && !args.create {
+		create_manifest_export(args, server, transport)?
+	}
+	else {
+		shared_export(args, server)?
+	};
 
-				success = branch_output.status.success();
+	if tree.symlinks().next().is_some() && !transport.can_exec() {
+		return Err(anyhow!("The export contains symlinks, which require ssh, use --dereference to upload copies instead"));
+	}
 
-				if success {
+	hooks::run_local(args, Stage::BeforeUpload, context)?;
 
-					env::set_current_dir(export_path.clone()).unwrap();
+	let base = base_dest(args);
 
-					let branch = String::from_utf8(branch_output.stdout).unwrap();
+	let _ = transport.mkdir(&base);
 
-					let branch = branch.trim_end();
+	let lock = Lock::acquire(args, &base, transport)?;
 
-					if verbose {
-						println!();
-						println!("Creating revision.json");
-					}
+	let result = transfer(args, &tree, context, target, transport, connect);
 
-					let revision = Revision::new(head_ref, branch);
+	record_history(args, &base, context, target, &result, transport);
 
-					let revision_json = serde_json::to_string(&revision).unwrap();
+	let released = lock.release(transport);
 
-					let mut revision_path = export_path.clone();
+	let deployed = result?;
 
-					revision_path.push(deploy_path.clone());
-					revision_path.push(folder_name);
-					revision_path.push("revision.json");
+	released?;
 
-					if revision_path.exists() {
-						fs::remove_file(revision_path.clone()).unwrap();
-					}
+	Ok(deployed)
+}
 
-					let mut revision_file = fs::OpenOptions::new().write(true).create_new(true).open(revision_path).unwrap();
+/// Add the deployment to the history in `dest`, which only warns when it fails
+fn record_history(args: &Arguments, dest: &str, context: &Context, target: &report::Target, result: &Result<bool, anyhow::Error>, transport: &mut dyn Transport) {
 
-					revision_file.write_all(revision_json.as_bytes()).unwrap();
+	let mut entry = history::Entry::new(args);
 
-					println!();
-					println!("BRANCH: {}", &branch);
-					println!("HEAD: {}", &head_ref);
-					println!("SERVER: {}", &revision_file_server.admin.revision);
-					println!("LOCAL: {}", export_path.clone().to_slash().unwrap());
+	entry.branch = context.branch.clone();
+	entry.from = context.previous.clone();
+	entry.to = context.revision.clone();
+	entry.uploaded = target.uploaded;
+	entry.deleted = target.deleted;
 
-				}
-			}
-			else {
-				return Err(anyhow!(format!("ERROR: {:?}", cmd_output)));
-			}
+	match result {
+		Ok(_) => entry.result = String::from("deployed"),
+		Err(err) => {
+			entry.result = String::from("failed");
+			entry.error = secrets::mask(&err.to_string());
+		}
+	}
+
+	if let Err(err) = history::append(transport, dest, &entry) {
+		warn!("Could not update {}: {}", HISTORY_FILE, err);
+	}
+}
+
+fn transfer(args: &Arguments, tree: &Tree, context: &mut Context, target: &mut report::Target, transport: &mut dyn Transport, connect: &Connect) -> Result<bool, anyhow::Error> {
+
+	let dest = remote_dest(args).to_slash().unwrap().to_string();
+
+	let mut journal = Journal::open(args, &tree.head, &dest)?;
+
+	let dest_path = PathBuf::from(&journal.destination);
+
+	context.release_path = journal.destination.clone();
+
+	if !args.dist {
+
+		let mut replaced: Vec<&str> = tree.files()
+			.map(|(path, _)| path)
+			.filter(|path| !journal.is_done(path))
+			.collect();
+
+		replaced.extend(tree.removed.iter().map(String::as_str));
+
+		backup::run(args, &journal.destination, &replaced, transport)?;
+	}
+
+	let mut uploaded = false;
+
+	if args.archive {
+
+		if !transport.can_exec() {
+			info!();
+			info!("Archive upload requires ssh, uploading files one by one");
 		}
 		else {
-			return Err(anyhow!(format!("ERROR: {:?}", head_output)));
+			uploaded = Failure::Upload.tag(upload_archive(tree, &dest_path, target, transport))?;
+		}
+	}
+
+	if !uploaded {
+
+		let result = upload(args, tree, &dest_path, &mut journal, target, transport, connect);
+
+		if result.is_err() {
+			info!();
+			info!("Deployment incomplete, run again with --resume to continue");
 		}
 
-		Ok(export_path)
+		Failure::Upload.tag(result)?;
 	}
 
-	fn deploy(&self) -> Result<bool, anyhow::Error> {
+	for path in &tree.removed {
 
-		let args = &self.args;
-		let mut dest = args.destination.clone();
+		let result = transport.remove(&remote_path(&dest_path, path));
 
-		let mut stdout = std::io::stdout();
+		match result {
+			Ok(_) => {
+				debug!("Removed: {}", path);
 
-		let dist = args.dist;
-		let create = args.create;
+				target.deleted += 1;
+			}
+			Err(error) => warn!("Could not remove {}: {}", path, error)
+		}
+	}
+
+	permissions::apply(args, tree, &journal.destination, &[REVISION_FILE, MANIFEST_FILE], transport)?;
+
+	if args.commit_times {
+		set_commit_times(args, tree, &dest_path, transport)?;
+	}
+
+	hooks::run_remote(args, Stage::AfterUpload, context, transport)?;
 
-		if dest.starts_with('.') {
-			dest.remove(0);
+	// The server only claims the new revision once every other file is in place
+	for name in [MANIFEST_FILE, REVISION_FILE] {
+
+		if let Some((_, data)) = tree.files().find(|(path, _)| *path == name) {
+			Failure::Upload.tag(put_last(&dest_path, name, data, transport))?;
 		}
+	}
+
+	journal.finish()?;
+
+	hooks::run_remote(args, Stage::AfterRevision, context, transport)?;
+
+	Ok(true)
+}
 
-		let local_path = if dist || create {
-			self.create_dist()?
+fn upload(args: &Arguments, tree: &Tree, dest_path: &Path, journal: &mut Journal, target: &mut report::Target, transport: &mut dyn Transport, connect: &Connect) -> Result<(), anyhow::Error> {
+
+	info!();
+
+	for entry in &tree.entries {
+
+		let Content::Dir = entry.content else {
+			continue;
+		};
+
+		let export_path = if entry.path.is_empty() {
+			dest_path.to_path_buf()
 		}
 		else {
-			self.create_export()?
+			dest_path.join(&entry.path)
 		};
 
-		let mut dest_path = PathBuf::new();
+		let str_export = export_path.to_slash().unwrap().to_string();
+
+		let result = transport.mkdir(&str_export);
 
-		dest_path.push(dest);
+		match result {
+			Ok(_) => {
+				debug!("MKDIR: {}", str_export);
+			}
+			Err(error) => {
+				
+				debug!("Error: {}", error);
+			} 
+		}
+	}
+
+	let mut files = vec![];
 
-		if dist {
-			let time_stamp = chrono::offset::Local::now().format("%Y%m%d-%H%M%S").to_string();
+	for (path, data) in tree.files() {
 
-			dest_path.push(time_stamp);
+		if path != REVISION_FILE && path != MANIFEST_FILE && !journal.is_done(path) {
+			files.push((path, data.as_slice()));
 		}
+	}
 
-		let local_str = local_path.clone().to_string_lossy().to_string();
+	let links: Vec<(&str, &str)> = tree.symlinks()
+		.filter(|(path, _)| !journal.is_done(path))
+		.collect();
 
-		let mut count :u64 = 0;
-		let mut current: u64 = 0;
+	let mut progress = Progress::new(args, (files.len() + links.len()) as u64);
 
-		for entry in WalkDir::new(local_path.clone()).into_iter().filter_map(|e| e.ok()) {
+	if args.jobs <= 1 {
 
-			let meta_data = entry.metadata().unwrap();
+		for (path, data) in files {
 
-			if meta_data.is_file() {
-				count += 1;
-			}
+			progress.step(&file_name(path));
+
+			transport.put(&remote_path(dest_path, path), data)?;
+
+			target.add_upload(1, data.len() as u64);
+
+			journal.record(path)?;
 		}
+	}
+	else {
+		upload_parallel(args.jobs, files, dest_path, journal, target, connect, &mut progress)?;
+	}
 
-		println!();
+	for (path, target) in links {
 
-		let connection_str = format!("{}:{}", self.args.host, "21");
-		let mut ftp = FtpStream::connect(connection_str).unwrap();
+		progress.step(&file_name(path));
 
-		let _ = ftp.login(&self.args.user, &self.args.password);
+		transport.symlink(target, &remote_path(dest_path, path))?;
 
-		for entry in WalkDir::new(local_path.clone()).into_iter().filter_map(|e| e.ok()) {
+		journal.record(path)?;
+	}
 
-			let meta_data = entry.metadata().unwrap();
+	Ok(())
+}
 
-			let dest_export = entry.path().to_path_buf();
+/// Give uploaded files the time of the last commit touching them, where the server supports it
+fn set_commit_times(args: &Arguments, tree: &Tree, dest_path: &Path, transport: &mut dyn Transport) -> Result<(), anyhow::Error> {
 
-			let mut str_dest = String::from(dest_export.to_str().unwrap());
+	let times = git::commit_times(&PathBuf::from(&args.local), &tree.head)?;
 
-			str_dest = str_dest.replace(local_str.as_str(), dest_path.to_str().unwrap());
+	let paths: Vec<(String, i64)> = tree.files()
+		.filter_map(|(path, _)| times.get(&args.repo_path(path)).map(|time| (remote_path(dest_path, path), *time)))
+		.collect();
 
-			let export_dest = str_dest.trim();
+	let paths: Vec<(&str, i64)> = paths.iter().map(|(path, time)| (path.as_str(), *time)).collect();
 
-			let mut export_path = PathBuf::new();
+	match transport.set_mtimes(&paths) {
+		Ok(_) => {
+			debug!("Set commit times on {} files", paths.len());
+		},
+		Err(err) => warn!("Could not set modification times: {}", err)
+	}
 
-			export_path.push(export_dest);
+	Ok(())
+}
 
-			let str_export = export_path.to_slash().unwrap().to_string();
-			
-			if meta_data.is_file() {
+/// Upload `name` under a temporary name and move it into place so it is never seen half written
+fn put_last(dest_path: &Path, name: &str, data: &[u8], transport: &mut dyn Transport) -> Result<(), anyhow::Error> {
 
-				let entry_path = entry.path();
+	let final_path = remote_path(dest_path, name);
+	let temp_path = format!("{}.tmp-{}", final_path, std::process::id());
 
-				let file_name = entry_path.file_name().unwrap().to_string_lossy().to_string().replace('"', "");
+	transport.put(&temp_path, data)?;
+	transport.rename(&temp_path, &final_path)?;
 
-				let file_path = PathBuf::from(str_export);
+	debug!("Written: {}", final_path);
 
-				let parent_dir = file_path.parent().unwrap().to_slash().unwrap().to_string();
+	Ok(())
+}
+
+fn remote_path(dest_path: &Path, path: &str) -> String {
+
+	dest_path.join(path).to_slash().unwrap().to_string()
+}
+
+/// Upload `files` over `jobs` connections of their own, reporting progress in completion order
+fn upload_parallel(jobs: usize, files: Vec<(&str, &[u8])>, dest_path: &Path, journal: &mut Journal, target: &mut report::Target, connect: &Connect, progress: &mut Progress) -> Result<(), anyhow::Error> {
+
+	let queue = Mutex::new(files.into_iter().collect::<VecDeque<_>>());
 
-				ftp.mkdir(&parent_dir).unwrap_or_else(|err| {
-					println!("{err}");
-				});
+	let (sender, receiver) = mpsc::channel::<(Option<(&str, &[u8])>, Result<(), anyhow::Error>)>();
 
-				if current > 0 {
+	let mut errors = vec![];
 
-					let _ = stdout.execute(cursor::MoveUp(3));
-					let _ = stdout.execute(terminal::Clear(terminal::ClearType::FromCursorDown));
+	thread::scope(|scope| {
+
+		for _ in 0..jobs {
+
+			let sender = sender.clone();
+			let queue = &queue;
+
+			scope.spawn(move || {
+
+				let mut transport = match connect() {
+					Ok(transport) => transport,
+					Err(err) => {
+						let _ = sender.send((None, Err(err)));
+						return;
+					}
+				};
+
+				loop {
+
+					let next = queue.lock().unwrap().pop_front();
+
+					let Some((path, data)) = next else {
+						break;
+					};
+
+					let result = transport.put(&remote_path(dest_path, path), data);
+
+					let _ = sender.send((Some((path, data)), result));
 				}
-				
-				current += 1;
+			});
+		}
+
+		drop(sender);
+
+		for (path, result) in receiver {
 
-				let progress = ((current as f64 / count as f64) * 100.0) as i32;
+			match (path, result) {
+				(Some((path, data)), Ok(_)) => {
 
-				writeln!(stdout, "Deploying: {progress}%").unwrap();
-				writeln!(stdout, "{file_name}").unwrap();
-				writeln!(stdout, "{current} / {count}").unwrap();
+					progress.step(&file_name(path));
 
-				let mut file = OpenOptions::new().read(true).open(entry_path).unwrap();
+					target.add_upload(1, data.len() as u64);
 
-				let _ = ftp.put(&file_path.to_string_lossy(), &mut file);
+					if let Err(err) = journal.record(path) {
+						errors.push(format!("Journal: {}", err));
+					}
+				},
+				(Some((path, _)), Err(err)) => errors.push(format!("{}: {}", path, err)),
+				(None, Err(err)) => errors.push(format!("Connection: {}", err)),
+				(None, Ok(_)) => {}
 			}
 		}
+	});
+
+	let remaining = queue.lock().unwrap().len();
+
+	if remaining > 0 {
+		errors.push(format!("{} files were not uploaded", remaining));
+	}
+
+	if !errors.is_empty() {
+		return Err(anyhow!("Upload failed:\n{}", errors.join("\n")));
+	}
+
+	Ok(())
+}
+
+/// Upload the tree as one tarball and unpack it remotely, returning false when the server lacks the tools for it
+fn upload_archive(tree: &Tree, dest_path: &Path, target: &mut report::Target, transport: &mut dyn Transport) -> Result<bool, anyhow::Error> {
+
+	if transport.exec("command -v tar && command -v gzip && command -v sha256sum").is_err() {
+
+		info!();
+		info!("Server lacks tar, gzip or sha256sum, uploading files one by one");
+
+		return Ok(false);
+	}
+
+	let dest_path = dest_path.to_slash().unwrap().to_string();
+
+	let data = tree.pack(&[REVISION_FILE, MANIFEST_FILE])?;
+	let checksum = export::sha256(&data);
+
+	let archive_path = format!("{}/.deploy-{}.tar.gz", dest_path, std::process::id());
+
+	info!();
+	info!("Uploading archive: {} files, {} bytes", tree.file_count(), data.len());
+
+	transport.mkdir(&dest_path)?;
+	transport.put(&archive_path, &data)?;
+
+	let remote_sum = transport.exec(&format!("sha256sum {}", transport::quote(&archive_path)))?;
+
+	if remote_sum.split_whitespace().next() != Some(checksum.as_str()) {
+
+		let _ = transport.exec(&format!("rm -f {}", transport::quote(&archive_path)));
+
+		return Err(Failure::Verification.wrap(anyhow!("Checksum mismatch for {}", archive_path)));
+	}
+
+	debug!("SHA256: {}", checksum);
+
+	let result = transport.exec(&format!("tar -xzf {} -C {}", transport::quote(&archive_path), transport::quote(&dest_path)));
+
+	transport.exec(&format!("rm -f {}", transport::quote(&archive_path)))?;
+
+	result?;
+
+	let files = tree.files().filter(|(path, _)| *path != REVISION_FILE && *path != MANIFEST_FILE).count();
+
+	target.add_upload(files, data.len() as u64);
+
+	info!("Unpacked into {}", dest_path);
+
+	Ok(true)
+}
+
+pub struct FtpExport<'a> {
+	pub args: Arguments,
+	pub files: Vec<&'a str>
+}
+
+impl<'a> FtpExport<'a> {
+
+	pub fn new(args: Arguments) -> Result<Self, anyhow::Error> {
+
+		Self::open(prepare(args)?)
+	}
+
+	/// Use arguments already passed through `prepare`
+	pub fn open(args: Arguments) -> Result<Self, anyhow::Error> {
+
+		check_target(&args)?;
+
+		Ok(Self {
+			args: args.clone(),
+			files: vec![]
+		})
+	}
+
+	fn git_pull(&self) -> Result<(), anyhow::Error> {
+
+		git_pull(&self.args)
+	}
+
+	fn get_revision_file(&self) -> Result<Revision, anyhow::Error> {
+		
+		let mut folder = self.args.destination.clone();
+
+		if folder.starts_with('.') {
+			let mut str = folder.to_string();
+			str.remove(0);
+			folder = str;
+		}
+
+		let connection_str = format!("{}:{}", self.args.host, "21");
+		let mut ftp = Failure::Connection.tag(FtpStream::connect(connection_str))?;
+
+		Failure::Auth.tag(ftp.login(&self.args.user, &self.args.password))?;
+
+		let file = format!("{}/revision.json", folder);
+
+		let mut recived = ftp.get(&file)?;
+
+		let mut content = String::from("");
+
+		let _ = recived.read_to_string(&mut content);
+
+		let revision = serde_json::from_str::<Revision>(&content)?;
 
 		let _ = ftp.quit();
 
-		Ok(true)
+		Ok(revision)
+	}
+
+	fn deploy(&self) -> Result<bool, anyhow::Error> {
+
+		let args = &self.args;
+
+		let server = if args.dist || args.create {
+			None
+		}
+		else if args.manifest {
+			self.get_revision_file().ok()
+		}
+		else {
+			Some(self.get_revision_file()?)
+		};
+
+		let mut transport = FtpTransport::connect(args)?;
+
+		let connect = || -> Result<Box<dyn Transport + Send>, anyhow::Error> {
+			Ok(Box::new(FtpTransport::connect(args)?))
+		};
+
+		deploy_tree(args, server, &mut transport, &connect)
+	}
+
+	fn drift(&self, download: Option<&str>) -> Result<bool, anyhow::Error> {
+
+		let server = self.get_revision_file()?;
+
+		let mut transport = FtpTransport::connect(&self.args)?;
+
+		check_drift(&self.args, &server, download, &mut transport)
+	}
+
+	fn history(&self, last: usize) -> Result<bool, anyhow::Error> {
+
+		let mut transport = FtpTransport::connect(&self.args)?;
+
+		show_history(&self.args, last, &mut transport)
+	}
+
+	fn run(&self) -> Result<bool, anyhow::Error> {
+
+		for_targets(&self.args, |args| {
+
+			let export = FtpExport {
+				args,
+				files: vec![]
+			};
+
+			match &export.args.command {
+				Some(Commands::Drift { download }) => export.drift(download.as_deref()),
+				Some(Commands::History { last }) => export.history(*last),
+				None => export.deploy()
+			}
+		})
 	}
 }
 
@@ -1245,6 +1642,165 @@ impl <'a> Executor for FtpExport<'a> {
 		
 		self.git_pull()?;
 
-		self.deploy()
+		self.run()
+	}
+}
+
+/// Revision deployed to each target of a host, empty where there is none yet
+fn server_revisions(args: Arguments) -> Result<Vec<String>, anyhow::Error> {
+
+	let revision = |result: Result<Revision, anyhow::Error>| result.map(|revision| revision.admin.revision).unwrap_or_default();
+
+	if args.new {
+
+		let export = FtpExport::open(args)?;
+
+		Ok(targets(&export.args).into_iter()
+			.map(|args| revision(FtpExport { args, files: vec![] }.get_revision_file()))
+			.collect())
+	}
+	else {
+
+		let export = Export::open(args)?;
+
+		Ok(targets(&export.args).into_iter()
+			.map(|args| revision(Export { session: export.session.clone(), args, files: vec![] }.get_revision_file()))
+			.collect())
+	}
+}
+
+/// Deploys to every host listed in the profile, from one pull
+pub struct HostsExport {
+	pub args: Arguments
+}
+
+impl HostsExport {
+
+	pub fn new(args: Arguments) -> Self {
+
+		Self {
+			args
+		}
+	}
+}
+
+impl Executor for HostsExport {
+
+	fn execute(&self) -> Result<bool, anyhow::Error> {
+
+		git_pull(&self.args)?;
+
+		hosts::run(&self.args, server_revisions, |args| {
+
+			if args.new {
+				FtpExport::open(args)?.run()
+			}
+			else {
+				Export::open(args)?.run()
+			}
+		})
+	}
+}
+
+#[cfg(test)]
+mod tests {
+
+	use super::*;
+	use clap::Parser;
+	use crate::config::Mapping;
+
+	fn mapped(source: &str, mappings: &[(&str, &str)]) -> Vec<(String, String)> {
+
+		let mut args = Arguments::parse_from(["repo-executor", "--dest", "/www/site/", "--source", source]);
+
+		args.settings.mappings = mappings.iter()
+			.map(|(source, target)| Mapping { source: source.to_string(), target: target.to_string() })
+			.collect();
+
+		targets(&args).into_iter().map(|target| (target.source, target.destination)).collect()
+	}
+
+	#[test]
+	fn targets_follow_mappings() {
+
+		assert_eq!(
+			mapped("", &[("public", "html"), ("api", "/srv/api")]),
+			[
+				(String::from("public"), String::from("/www/site/html")),
+				(String::from("api"), String::from("/srv/api"))
+			]
+		);
 	}
-}
\ No newline at end of file
+
+	fn git(repo: &Path, args: &[&str]) {
+
+		let status = Command::new("git")
+			.current_dir(repo)
+			.args(["-c", "user.name=test", "-c", "user.email=test@example.com"])
+			.args(args)
+			.stdout(Stdio::null())
+			.status()
+			.unwrap();
+
+		assert!(status.success(), "git {:?}", args);
+	}
+
+	#[cfg(unix)]
+	#[test]
+	fn incremental_export_copies_whole_link_targets() {
+
+		let repo = env::temp_dir().join(format!("repo-executor-links-{}", std::process::id()));
+
+		let _ = fs::remove_dir_all(&repo);
+		fs::create_dir_all(repo.join("shared")).unwrap();
+
+		fs::write(repo.join("shared/a.txt"), "a1").unwrap();
+		fs::write(repo.join("shared/b.txt"), "b").unwrap();
+		std::os::unix::fs::symlink("shared", repo.join("web")).unwrap();
+
+		git(&repo, &["init", "-q"]);
+		git(&repo, &["add", "-A"]);
+		git(&repo, &["commit", "-q", "-m", "first"]);
+
+		let first = git::head_ref(&repo).unwrap();
+
+		// The target of an unchanged link changes, and a new link comes in
+		fs::write(repo.join("shared/a.txt"), "a2").unwrap();
+		std::os::unix::fs::symlink("shared", repo.join("docs")).unwrap();
+
+		git(&repo, &["add", "-A"]);
+		git(&repo, &["commit", "-q", "-m", "second"]);
+
+		let args = Arguments::parse_from(["repo-executor", "--dereference", "--local", repo.to_str().unwrap()]);
+
+		let tree = create_export(&args, &Revision::new(&first, "master"));
+
+		let _ = fs::remove_dir_all(&repo);
+
+		let tree = tree.unwrap();
+
+		let mut files: Vec<(&str, &[u8])> = tree.files()
+			.filter(|(path, _)| *path != REVISION_FILE)
+			.map(|(path, data)| (path, data.as_slice()))
+			.collect();
+
+		files.sort();
+
+		assert_eq!(files, [
+			("docs/a.txt", b"a2".as_slice()),
+			("docs/b.txt", b"b".as_slice()),
+			("shared/a.txt", b"a2".as_slice()),
+			("web/a.txt", b"a2".as_slice()),
+			("web/b.txt", b"b".as_slice())
+		]);
+
+		assert!(tree.symlinks().next().is_none());
+	}
+
+	#[test]
+	fn source_overrides_mappings() {
+
+		assert_eq!(mapped("public", &[("api", "/srv/api")]), [(String::from("public"), String::from("/www/site/"))]);
+		assert_eq!(mapped("", &[]), [(String::new(), String::from("/www/site/"))]);
+	}
+}
@@ -0,0 +1,109 @@
+use std::{collections::HashSet, fs, path::PathBuf};
+use chrono::Local;
+
+use crate::{cli::Arguments, export::Tree, info, transport::{self, Transport}};
+
+pub const BACKUP_DIR: &str = ".backups";
+
+/// Set aside the files in `dest` that are about to be replaced or deleted, so a deployment can be undone
+pub fn run(args: &Arguments, dest: &str, paths: &[&str], transport: &mut dyn Transport) -> Result<(), anyhow::Error> {
+
+	if !args.backup && args.backup_local.is_none() {
+		return Ok(());
+	}
+
+	let stamp = Local::now().format("%Y%m%d-%H%M%S").to_string();
+
+	if args.backup {
+
+		let backup = format!("{}/{}", BACKUP_DIR, stamp);
+
+		let count = if transport.can_exec() {
+			copy_remote(dest, &backup, paths, transport)?
+		}
+		else {
+			copy_through(dest, &backup, paths, transport)?
+		};
+
+		info!();
+		info!("Backed up {} files to {}/{}", count, dest, backup);
+	}
+
+	if let Some(dir) = &args.backup_local {
+
+		let mut tree = Tree::new();
+
+		for path in paths {
+
+			if let Ok(data) = transport.get(&format!("{}/{}", dest, path)) {
+				tree.add_file(path, data);
+			}
+		}
+
+		let dir = PathBuf::from(shellexpand::full(dir)?.to_string());
+
+		fs::create_dir_all(&dir)?;
+
+		let archive_path = dir.join(format!("backup-{}.tar.gz", stamp));
+
+		fs::write(&archive_path, tree.pack(&[])?)?;
+
+		info!();
+		info!("Backed up {} files to {}", tree.file_count(), archive_path.to_string_lossy());
+	}
+
+	Ok(())
+}
+
+/// Copy on the server itself, skipping files that do not exist yet
+fn copy_remote(dest: &str, backup: &str, paths: &[&str], transport: &mut dyn Transport) -> Result<usize, anyhow::Error> {
+
+	let mut count = 0;
+
+	for chunk in paths.chunks(200) {
+
+		let files: Vec<String> = chunk.iter().map(|path| transport::quote(path)).collect();
+
+		let cmd = format!(
+			"cd {dest} && for f in {files}; do if [ -f \"$f\" ]; then mkdir -p {backup}/\"$(dirname \"$f\")\" && cp -p \"$f\" {backup}/\"$f\" && echo \"$f\"; fi; done",
+			dest = transport::quote(dest),
+			files = files.join(" "),
+			backup = transport::quote(backup)
+		);
+
+		count += transport.exec(&cmd)?.lines().count();
+	}
+
+	Ok(count)
+}
+
+/// Download each file and upload it again below the backup directory, for servers without a shell
+fn copy_through(dest: &str, backup: &str, paths: &[&str], transport: &mut dyn Transport) -> Result<usize, anyhow::Error> {
+
+	let mut dirs = HashSet::new();
+	let mut count = 0;
+
+	for path in paths {
+
+		let Ok(data) = transport.get(&format!("{}/{}", dest, path)) else {
+			continue;
+		};
+
+		let target = format!("{}/{}", backup, path);
+
+		for (index, _) in target.match_indices('/') {
+
+			let dir = &target[..index];
+
+			if dirs.insert(dir.to_string()) {
+				let _ = transport.mkdir(&format!("{}/{}", dest, dir));
+			}
+		}
+
+		transport.put(&format!("{}/{}", dest, target), &data)?;
+
+		count += 1;
+	}
+
+	Ok(count)
+}
@@ -0,0 +1,88 @@
+use std::{fs, path::PathBuf, process::Stdio, sync::Mutex};
+use anyhow::anyhow;
+use path_slash::PathExt;
+use walkdir::WalkDir;
+
+use crate::{cli::Arguments, export, hooks, info, log, secrets};
+
+static BUILT: Mutex<Option<Artifacts>> = Mutex::new(None);
+
+/// Files produced by the configured build command
+#[derive(Clone)]
+pub struct Artifacts {
+	pub files: Vec<(String, Vec<u8>)>,
+	pub hash: String
+}
+
+/// Run the build command in the repository and collect its declared outputs, if a build is configured, once per run
+pub fn run(args: &Arguments) -> Result<Option<Artifacts>, anyhow::Error> {
+
+	let Some(build) = &args.settings.build else {
+		return Ok(None);
+	};
+
+	let mut built = BUILT.lock().unwrap();
+
+	if let Some(artifacts) = built.as_ref() {
+		return Ok(Some(artifacts.clone()));
+	}
+
+	let local_path = PathBuf::from(&args.local);
+
+	if !build.command.is_empty() {
+
+		info!();
+		info!("BUILD: {}", secrets::mask(&build.command));
+		info!();
+
+		let status = hooks::shell(&build.command)
+			.current_dir(&local_path)
+			.stdout(log::child_output())
+			.stderr(Stdio::inherit())
+			.status()?;
+
+		if !status.success() {
+			return Err(anyhow!("Build `{}` failed with {}", secrets::mask(&build.command), status));
+		}
+	}
+
+	let mut files = vec![];
+
+	for output in &build.outputs {
+
+		let output_path = local_path.join(output);
+
+		if !output_path.exists() {
+			return Err(anyhow!("Build output {} does not exist", output));
+		}
+
+		for entry in WalkDir::new(&output_path).into_iter().filter_map(|e| e.ok()) {
+
+			if !entry.file_type().is_file() {
+				continue;
+			}
+
+			let path = entry.path().strip_prefix(&local_path)?.to_slash().unwrap().to_string();
+
+			files.push((path, fs::read(entry.path())?));
+		}
+	}
+
+	files.sort_by(|a, b| a.0.cmp(&b.0));
+	files.dedup_by(|a, b| a.0 == b.0);
+
+	let mut digest = String::new();
+
+	for (path, data) in &files {
+		digest.push_str(&format!("{} {}\n", export::sha256(data), path));
+	}
+
+	let artifacts = Artifacts {
+		files,
+		hash: export::sha256(digest.as_bytes())
+	};
+
+	*built = Some(artifacts.clone());
+
+	Ok(Some(artifacts))
+}
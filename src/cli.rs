@@ -1,23 +1,27 @@
-use clap::Parser;
+use clap::{Parser, Subcommand, ValueEnum};
+
+use crate::{config::Config, secrets::Secrets};
 
 #[derive(Parser, Debug, Clone)]
 #[command(author, version, about = "Utility to update product")]
 #[clap(disable_help_flag = true)]
-
 pub struct Arguments {
 
-	#[arg(short('h'),long, help="Host")]
+	#[arg(short('h'),long, default_value = "", help="Host ( required unless the profile lists hosts )")]
 	pub host: String,
 
-	#[arg(short, long, help="Username")]
+	#[arg(short, long, default_value = "", help="Username")]
 	pub user: String,
 
-	#[arg(short('w'), long, help="Password")]
+	#[arg(short('w'), long, default_value = "", help="Password")]
 	pub password: String,
 
-	#[arg(short('d'), long("dest"), help="Upload destination")]
+	#[arg(short('d'), long("dest"), default_value = "", help="Upload destination")]
 	pub destination: String,
 
+	#[arg(long, default_value = "", value_name="DIR", help="Deploy only this directory of the repository ( instead of the config mappings )")]
+	pub source: String,
+
 	#[arg(short('c'), long, help="Create export")]
 	pub create: bool,
 
@@ -40,12 +44,168 @@ pub struct Arguments {
 	#[arg(short('v'), long("verbose"), help="Verbose output")]
 	pub verbose: bool,
 
-	#[arg(short('s'), long("stdprint"), help="Verbose print to stdout")]
+	#[arg(short('s'), long("stdprint"), help="Print verbose output to stdout, also when it goes to the --log file")]
 	pub stdprint: bool,
 
 	#[arg(short('n'), help="Use ftp instead off ssh")]
 	pub new: bool,
+
+	#[arg(long, help="Resume an interrupted deployment")]
+	pub resume: bool,
+
+	#[arg(long("force-unlock"), help="Remove a lock left on the server before deploying")]
+	pub force_unlock: bool,
+
+	#[arg(long("lock-timeout"), default_value_t = 30, value_name="MINUTES", help="Age after which a lock is considered stale")]
+	pub lock_timeout: u64,
+
+	#[arg(short('j'), long, default_value_t = 1, help="Number of parallel uploads")]
+	pub jobs: usize,
+
+	#[arg(long, help="Upload files whose hash differs from the manifest on the server and delete those no longer deployed")]
+	pub manifest: bool,
+
+	#[arg(long, help="Copy files about to be replaced or deleted into .backups/<timestamp> in the destination")]
+	pub backup: bool,
+
+	#[arg(long("backup-local"), value_name="DIR", help="Save files about to be replaced or deleted to a tar.gz in DIR")]
+	pub backup_local: Option<String>,
+
+	#[arg(long("commit-times"), help="Set the mtime of uploaded files to their last commit")]
+	pub commit_times: bool,
+
+	#[arg(long, help="Upload copies of what symlinks point to instead of the links ( needed for ftp )")]
+	pub dereference: bool,
+
+	#[arg(long, help="Upload a single tar.gz and unpack it on the server ( ssh )")]
+	pub archive: bool,
+
+	#[arg(long("temp-dir"), help="Write the export to a temp directory before upload")]
+	pub temp_dir: bool,
+
+	#[arg(long, value_name="GLOB", help="Exclude files matching glob ( repeatable )")]
+	pub exclude: Vec<String>,
+
+	#[arg(long, value_name="GLOB", help="Deploy files matching glob even if excluded ( repeatable )")]
+	pub include: Vec<String>,
 	
+	#[arg(long, default_value = "", help="Config file ( default .deploy.json in the repo )")]
+	pub config: String,
+
+	#[arg(long, default_value = "", help="Profile from the config to deploy with")]
+	pub profile: String,
+
+	#[arg(long, default_value = "", value_name="FILE", help="Secrets file ( overrides the config )")]
+	pub secrets: String,
+
+	#[arg(long, value_enum, default_value_t = Output::Text, help="Print a JSON report to stdout, and everything else to stderr")]
+	pub output: Output,
+
+	#[arg(long, value_name="DIR", help="Write a timestamped log of the deployment, remote commands included, to a file in DIR")]
+	pub log: Option<String>,
+
+	#[arg(skip)]
+	pub settings: Config,
+
+	#[arg(skip)]
+	pub vault: Secrets,
+
+	/// Set while other hosts deploy at the same time, so progress is logged per host instead of redrawn
+	#[arg(skip)]
+	pub parallel_hosts: bool,
+
+	#[command(subcommand)]
+	pub command: Option<Commands>,
+
 	#[arg(short('H'), long("help"), help="Print help", action = clap::ArgAction::Help)]
 	pub help: Option<bool>,
-}
\ No newline at end of file
+}
+
+impl Arguments {
+
+	/// Path of a repository file below `--source`, relative to it, None for files outside
+	pub fn source_path<'a>(&self, path: &'a str) -> Option<&'a str> {
+
+		let source = self.source.trim_matches('/');
+
+		if source.is_empty() {
+			return Some(path);
+		}
+
+		path.strip_prefix(source)?.strip_prefix('/')
+	}
+
+	/// Path in the repository of a file deployed as `path`, the reverse of `source_path`
+	pub fn repo_path(&self, path: &str) -> String {
+
+		let source = self.source.trim_matches('/');
+
+		if source.is_empty() {
+			return path.to_string();
+		}
+
+		format!("{}/{}", source, path)
+	}
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq)]
+pub enum Output {
+	Text,
+	Json
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum Commands {
+	/// Report files changed on the server since the last deployment ( sha256 over ssh, sizes over ftp )
+	Drift {
+		#[arg(long, value_name="DIR", help="Download modified files into DIR")]
+		download: Option<String>
+	},
+	/// Show past deployments recorded on the server, newest first
+	History {
+		#[arg(long, default_value_t = 20, value_name="N", help="Number of deployments to show")]
+		last: usize
+	}
+}
+
+#[cfg(test)]
+mod tests {
+
+	use super::*;
+
+	fn with_source(source: &str) -> Arguments {
+
+		Arguments::parse_from(["repo-executor", "--source", source])
+	}
+
+	#[test]
+	fn empty_source_maps_every_path() {
+
+		for args in [with_source(""), with_source("/")] {
+			assert_eq!(args.source_path("a/b.php"), Some("a/b.php"));
+			assert_eq!(args.repo_path("a/b.php"), "a/b.php");
+		}
+	}
+
+	#[test]
+	fn source_slashes_are_ignored() {
+
+		for source in ["public", "/public", "public/", "/public/"] {
+
+			let args = with_source(source);
+
+			assert_eq!(args.source_path("public/css/a.css"), Some("css/a.css"));
+			assert_eq!(args.repo_path("css/a.css"), "public/css/a.css");
+		}
+	}
+
+	#[test]
+	fn paths_outside_the_source() {
+
+		let args = with_source("public");
+
+		assert_eq!(args.source_path("private/a.php"), None);
+		assert_eq!(args.source_path("public_html/a.php"), None);
+		assert_eq!(args.source_path("public"), None);
+	}
+}
@@ -0,0 +1,136 @@
+use std::{collections::BTreeMap, fs, path::PathBuf};
+use anyhow::anyhow;
+use serde::Deserialize;
+
+use crate::cli::Arguments;
+
+pub const CONFIG_FILE: &str = ".deploy.json";
+
+/// Commands run around a deployment, see `hooks`
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct Hooks {
+	pub before_export: Vec<String>,
+	pub before_upload: Vec<String>,
+	pub after_upload: Vec<String>,
+	pub after_revision: Vec<String>,
+	pub on_failure: Vec<String>
+}
+
+/// Command producing untracked files that are deployed along with the repository
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct Build {
+	pub command: String,
+	pub outputs: Vec<String>
+}
+
+/// Mode for the paths matching `glob`
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct ModeOverride {
+	pub glob: String,
+	pub mode: String
+}
+
+/// Remote permissions, modes are octal strings and the last matching override wins
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct Permissions {
+	pub file: String,
+	pub dir: String,
+	pub overrides: Vec<ModeOverride>,
+	pub owner: String,
+	pub group: String
+}
+
+/// Server to deploy to, empty fields and an unset `ftp` fall back to the command line
+///
+/// The password comes from the secrets file as `hosts.<host>.password`, `password` is only read to refuse it in the config.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct Host {
+	pub host: String,
+	pub user: String,
+	pub password: String,
+	pub destination: String,
+	pub ftp: Option<bool>
+}
+
+/// Whether a host failing stops the hosts not deployed yet
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum FailurePolicy {
+	#[default]
+	Abort,
+	Continue
+}
+
+/// Settings for one environment, selected with `--profile`
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct Profile {
+	pub variables: BTreeMap<String, String>,
+	pub secrets: String,
+	pub hosts: Vec<Host>,
+	pub parallel: bool,
+	pub on_host_failure: FailurePolicy
+}
+
+/// Deploy the repository directory `source` to `target`, relative to the destination unless absolute
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct Mapping {
+	pub source: String,
+	pub target: String
+}
+
+/// Settings read from `.deploy.json` in the repository, or the file given with `--config`
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct Config {
+	pub hooks: Hooks,
+	pub build: Option<Build>,
+	pub permissions: Option<Permissions>,
+	pub variables: BTreeMap<String, String>,
+	pub secrets: String,
+	pub mappings: Vec<Mapping>,
+	pub profiles: BTreeMap<String, Profile>
+}
+
+impl Config {
+
+	pub fn load(args: &Arguments) -> Result<Config, anyhow::Error> {
+
+		let path = if args.config.is_empty() {
+
+			let path = PathBuf::from(&args.local).join(CONFIG_FILE);
+
+			if !path.exists() && args.profile.is_empty() {
+				return Ok(Config::default());
+			}
+
+			path
+		}
+		else {
+			PathBuf::from(shellexpand::full(&args.config)?.to_string())
+		};
+
+		let content = fs::read_to_string(&path)
+			.map_err(|err| anyhow!("Could not read {}: {}", path.to_string_lossy(), err))?;
+
+		let config = serde_json::from_str::<Config>(&content)
+			.map_err(|err| anyhow!("Invalid config {}: {}", path.to_string_lossy(), err))?;
+
+		if !args.profile.is_empty() && !config.profiles.contains_key(&args.profile) {
+			return Err(anyhow!("Profile {} is not defined in {}", args.profile, path.to_string_lossy()));
+		}
+
+		Ok(config)
+	}
+
+	pub fn profile(&self, name: &str) -> Option<&Profile> {
+
+		self.profiles.get(name)
+	}
+}
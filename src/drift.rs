@@ -0,0 +1,178 @@
+use std::{collections::{BTreeMap, HashSet}, fs, path::Path};
+
+use crate::{info, transport::{RemoteFile, Transport}};
+
+/// What a deployed file should look like, the size is unknown for files only listed in the manifest
+pub struct Expected {
+	pub size: Option<u64>,
+	pub hash: String
+}
+
+#[derive(Default)]
+pub struct Report {
+	pub modified: Vec<String>,
+	pub missing: Vec<String>,
+	pub unexpected: Vec<String>
+}
+
+impl Report {
+
+	pub fn is_clean(&self) -> bool {
+
+		self.modified.is_empty() && self.missing.is_empty() && self.unexpected.is_empty()
+	}
+
+	pub fn print(&self) {
+
+		info!();
+
+		if self.is_clean() {
+			info!("No drift, the server matches the deployed revision");
+			return;
+		}
+
+		for (label, paths) in [("MODIFIED", &self.modified), ("MISSING", &self.missing), ("UNEXPECTED", &self.unexpected)] {
+
+			for path in paths {
+				info!("{}: {}", label, path);
+			}
+		}
+
+		info!();
+		info!("{} modified, {} missing, {} unexpected", self.modified.len(), self.missing.len(), self.unexpected.len());
+	}
+}
+
+/// Compare the files on the server with the expected ones, a file counts as modified when its hash or, lacking that, its size differs
+pub fn compare(expected: &BTreeMap<String, Expected>, remote: &[RemoteFile], ignore: impl Fn(&str) -> bool) -> Report {
+
+	let mut report = Report::default();
+
+	let mut seen = HashSet::new();
+
+	for file in remote {
+
+		seen.insert(file.path.as_str());
+
+		let Some(wanted) = expected.get(&file.path) else {
+
+			if !ignore(&file.path) {
+				report.unexpected.push(file.path.clone());
+			}
+
+			continue;
+		};
+
+		let modified = match (&file.hash, file.size, wanted.size) {
+			(Some(hash), _, _) => *hash != wanted.hash,
+			(None, Some(size), Some(wanted_size)) => size != wanted_size,
+			_ => false
+		};
+
+		if modified {
+			report.modified.push(file.path.clone());
+		}
+	}
+
+	for path in expected.keys() {
+
+		if !seen.contains(path.as_str()) {
+			report.missing.push(path.clone());
+		}
+	}
+
+	report.modified.sort();
+	report.unexpected.sort();
+
+	report
+}
+
+/// Download `paths` below `dest` on the server into `dir`, for review
+pub fn download(paths: &[String], dest: &str, dir: &Path, transport: &mut dyn Transport) -> Result<(), anyhow::Error> {
+
+	for path in paths {
+
+		let data = transport.get(&format!("{}/{}", dest, path))?;
+
+		let local_path = dir.join(path);
+
+		if let Some(parent) = local_path.parent() {
+			fs::create_dir_all(parent)?;
+		}
+
+		fs::write(&local_path, data)?;
+	}
+
+	info!("Downloaded {} files to {}", paths.len(), dir.to_string_lossy());
+
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+
+	use super::*;
+
+	fn expected(files: &[(&str, &str, Option<u64>)]) -> BTreeMap<String, Expected> {
+
+		files.iter()
+			.map(|(path, hash, size)| (path.to_string(), Expected { size: *size, hash: hash.to_string() }))
+			.collect()
+	}
+
+	fn remote(path: &str, hash: Option<&str>, size: Option<u64>) -> RemoteFile {
+
+		RemoteFile {
+			path: path.to_string(),
+			size,
+			hash: hash.map(String::from)
+		}
+	}
+
+	#[test]
+	fn compare_by_hash() {
+
+		let expected = expected(&[("a", "1", Some(1)), ("b", "2", Some(1)), ("c", "3", Some(1))]);
+		let remote = [remote("b", Some("changed"), None), remote("a", Some("1"), None), remote("d", Some("4"), None)];
+
+		let report = compare(&expected, &remote, |_| false);
+
+		assert_eq!(report.modified, ["b"]);
+		assert_eq!(report.missing, ["c"]);
+		assert_eq!(report.unexpected, ["d"]);
+		assert!(!report.is_clean());
+	}
+
+	#[test]
+	fn compare_by_size_without_hashes() {
+
+		let expected = expected(&[("a", "1", Some(10)), ("b", "2", Some(10)), ("c", "3", None)]);
+		let remote = [remote("a", None, Some(10)), remote("b", None, Some(11)), remote("c", None, Some(99))];
+
+		let report = compare(&expected, &remote, |_| false);
+
+		// Without a known size, a file listed only in the manifest cannot be told modified over ftp
+		assert_eq!(report.modified, ["b"]);
+		assert!(report.missing.is_empty());
+	}
+
+	#[test]
+	fn compare_ignores_unexpected_paths_only() {
+
+		let expected = expected(&[("a", "1", None)]);
+		let remote = [remote("a", Some("changed"), None), remote("revision.json", Some("x"), None), remote("cache/x", Some("y"), None)];
+
+		let report = compare(&expected, &remote, |path| path == "revision.json" || path == "a");
+
+		assert_eq!(report.modified, ["a"]);
+		assert_eq!(report.unexpected, ["cache/x"]);
+	}
+
+	#[test]
+	fn compare_clean() {
+
+		let expected = expected(&[("a", "1", None)]);
+
+		assert!(compare(&expected, &[remote("a", Some("1"), None)], |_| false).is_clean());
+	}
+}
@@ -0,0 +1,441 @@
+use std::{collections::{BTreeMap, HashMap, HashSet}, fs, io::{Read, Write}, path::Path, process::{Command, Stdio}};
+use anyhow::anyhow;
+use flate2::{write::GzEncoder, Compression};
+use sha2::{Digest, Sha256};
+use tar::{Archive, Builder, EntryType, Header};
+
+use crate::report::Failure;
+
+#[derive(Clone)]
+pub enum Content {
+	Dir,
+	File(Vec<u8>),
+	Symlink(String)
+}
+
+#[derive(Clone)]
+pub struct Entry {
+	pub path: String,
+	pub content: Content,
+	pub executable: bool,
+	/// Holds secret values and must never be written to disk
+	pub secret: bool
+}
+
+/// Files to deploy, held in memory in upload order with every directory ahead of its contents
+#[derive(Clone)]
+pub struct Tree {
+	pub entries: Vec<Entry>,
+	pub head: String,
+	/// Paths to delete on the server
+	pub removed: Vec<String>,
+	/// SHA-256 of every rendered template, by output path
+	pub templates: BTreeMap<String, String>,
+	/// SHA-256 of every file from the secrets file
+	pub secrets: BTreeMap<String, String>,
+	dirs: HashSet<String>,
+	files: HashMap<String, usize>
+}
+
+impl Default for Tree {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+impl Tree {
+
+	pub fn new() -> Tree {
+
+		let mut dirs = HashSet::new();
+		dirs.insert(String::new());
+
+		Tree {
+			entries: vec![Entry { path: String::new(), content: Content::Dir, executable: false, secret: false }],
+			head: String::new(),
+			removed: vec![],
+			templates: BTreeMap::new(),
+			secrets: BTreeMap::new(),
+			dirs,
+			files: HashMap::new()
+		}
+	}
+
+	fn add_parents(&mut self, path: &str) {
+
+		for (index, _) in path.match_indices('/') {
+
+			let dir = &path[..index];
+
+			if self.dirs.insert(dir.to_string()) {
+				self.entries.push(Entry { path: dir.to_string(), content: Content::Dir, executable: false, secret: false });
+			}
+		}
+	}
+
+	/// Add a file or symlink, replacing the content of one already at `path`
+	fn add(&mut self, path: &str, content: Content) {
+
+		if let Some(index) = self.files.get(path) {
+
+			self.entries[*index].content = content;
+
+			return;
+		}
+
+		self.add_parents(path);
+
+		self.files.insert(path.to_string(), self.entries.len());
+
+		self.entries.push(Entry { path: path.to_string(), content, executable: false, secret: false });
+	}
+
+	pub fn add_file(&mut self, path: &str, data: Vec<u8>) {
+
+		self.add(path, Content::File(data));
+	}
+
+	pub fn add_symlink(&mut self, path: &str, target: &str) {
+
+		self.add(path, Content::Symlink(target.to_string()));
+	}
+
+	/// Mark the file at `path` as executable, if the tree has it
+	pub fn set_executable(&mut self, path: &str) {
+
+		if let Some(index) = self.files.get(path) {
+			self.entries[*index].executable = true;
+		}
+	}
+
+	pub fn files(&self) -> impl Iterator<Item = (&str, &Vec<u8>)> {
+
+		self.entries.iter().filter_map(|entry| match &entry.content {
+			Content::File(data) => Some((entry.path.as_str(), data)),
+			_ => None
+		})
+	}
+
+	pub fn set_secret(&mut self, path: &str) {
+
+		if let Some(index) = self.files.get(path) {
+			self.entries[*index].secret = true;
+		}
+	}
+
+	/// Take the file or symlink at `path` out of the tree, leaving its directories
+	pub fn remove(&mut self, path: &str) -> Option<Entry> {
+
+		let index = self.files.remove(path)?;
+
+		let entry = self.entries.remove(index);
+
+		for position in self.files.values_mut() {
+
+			if *position > index {
+				*position -= 1;
+			}
+		}
+
+		Some(entry)
+	}
+
+	/// The part of the tree below `prefix`, with paths relative to it
+	pub fn subtree(self, prefix: &str) -> Tree {
+
+		let prefix = format!("{}/", prefix.trim_matches('/'));
+
+		let strip = |map: BTreeMap<String, String>| -> BTreeMap<String, String> {
+			map.into_iter()
+				.filter_map(|(path, hash)| path.strip_prefix(&prefix).map(|path| (path.to_string(), hash)))
+				.collect()
+		};
+
+		let mut tree = Tree::new();
+
+		for entry in self.entries {
+
+			let Some(path) = entry.path.strip_prefix(&prefix) else {
+				continue;
+			};
+
+			match entry.content {
+				Content::File(data) => tree.add_file(path, data),
+				Content::Symlink(target) => tree.add_symlink(path, &target),
+				Content::Dir => continue
+			}
+
+			if entry.executable {
+				tree.set_executable(path);
+			}
+
+			if entry.secret {
+				tree.set_secret(path);
+			}
+		}
+
+		tree.head = self.head;
+		tree.removed = self.removed.iter()
+			.filter_map(|path| path.strip_prefix(&prefix).map(String::from))
+			.collect();
+		tree.templates = strip(self.templates);
+		tree.secrets = strip(self.secrets);
+
+		tree
+	}
+
+	pub fn symlinks(&self) -> impl Iterator<Item = (&str, &str)> {
+
+		self.entries.iter().filter_map(|entry| match &entry.content {
+			Content::Symlink(target) => Some((entry.path.as_str(), target.as_str())),
+			_ => None
+		})
+	}
+
+	/// Replace every symlink with a copy of what it points to, a file taken from `targets` or else from below `root` on disk,
+	/// a directory getting copies of the files `targets` holds below it, `targets` being this tree unless given
+	pub fn dereference(&mut self, root: &Path, targets: Option<&Tree>) -> Result<(), anyhow::Error> {
+
+		let links: Vec<(String, String)> = self.symlinks()
+			.map(|(path, target)| (path.to_string(), target.to_string()))
+			.collect();
+
+		for (path, target) in links {
+
+			let Some(resolved) = resolve_link(&path, &target) else {
+				return Err(anyhow!("Symlink {} points outside the export: {}", path, target));
+			};
+
+			let source = root.join(&resolved);
+
+			let targets = targets.unwrap_or(self);
+
+			if targets.dirs.contains(&resolved) || source.is_dir() {
+
+				let prefix = format!("{}/", resolved);
+
+				let copies: Vec<(String, Vec<u8>, bool)> = targets.entries.iter()
+					.filter_map(|entry| match (&entry.content, entry.path.strip_prefix(&prefix)) {
+						(Content::File(data), Some(relative)) => Some((format!("{}/{}", path, relative), data.clone(), entry.executable)),
+						_ => None
+					})
+					.collect();
+
+				let index = self.files.remove(&path).unwrap();
+
+				self.entries[index].content = Content::Dir;
+				self.dirs.insert(path.clone());
+
+				for (copy, data, executable) in copies {
+
+					self.add_file(&copy, data);
+
+					if executable {
+						self.set_executable(&copy);
+					}
+				}
+
+				continue;
+			}
+
+			let (data, executable) = match targets.files.get(&resolved).map(|index| &targets.entries[*index]) {
+				Some(Entry { content: Content::File(data), executable, .. }) => (data.clone(), *executable),
+				_ => (fs::read(&source).map_err(|err| anyhow!("Could not dereference symlink {} to {}: {}", path, target, err))?, false)
+			};
+
+			self.add_file(&path, data);
+
+			if executable {
+				self.set_executable(&path);
+			}
+		}
+
+		Ok(())
+	}
+
+	pub fn file_count(&self) -> u64 {
+
+		self.files().count() as u64
+	}
+
+	/// Write the tree below `dir`, for when the export is wanted on disk, leaving out secret files
+	pub fn write(&self, dir: &Path) -> Result<(), anyhow::Error> {
+
+		for entry in &self.entries {
+
+			if entry.secret {
+				continue;
+			}
+
+			let path = dir.join(&entry.path);
+
+			match &entry.content {
+				Content::Dir => fs::create_dir_all(path)?,
+				Content::File(data) => fs::write(path, data)?,
+				#[cfg(unix)]
+				Content::Symlink(target) => std::os::unix::fs::symlink(target, path)?,
+				#[cfg(not(unix))]
+				Content::Symlink(target) => fs::write(path, target)?
+			}
+		}
+
+		Ok(())
+	}
+
+	/// Pack the tree into a gzipped tarball rooted at the export directory, leaving out `skip`
+	pub fn pack(&self, skip: &[&str]) -> Result<Vec<u8>, anyhow::Error> {
+
+		let encoder = GzEncoder::new(vec![], Compression::default());
+
+		let mut builder = Builder::new(encoder);
+
+		let mtime = chrono::offset::Utc::now().timestamp() as u64;
+
+		for entry in &self.entries {
+
+			if entry.path.is_empty() || skip.contains(&entry.path.as_str()) {
+				continue;
+			}
+
+			let mut header = Header::new_gnu();
+
+			header.set_mtime(mtime);
+
+			match &entry.content {
+				Content::Dir => {
+					header.set_entry_type(EntryType::Directory);
+					header.set_mode(0o755);
+					header.set_size(0);
+
+					builder.append_data(&mut header, format!("{}/", entry.path), std::io::empty())?;
+				},
+				Content::File(data) => {
+					header.set_entry_type(EntryType::Regular);
+					header.set_mode(if entry.executable { 0o755 } else { 0o644 });
+					header.set_size(data.len() as u64);
+
+					builder.append_data(&mut header, &entry.path, data.as_slice())?;
+				},
+				Content::Symlink(target) => {
+					header.set_entry_type(EntryType::Symlink);
+					header.set_mode(0o777);
+					header.set_size(0);
+
+					builder.append_link(&mut header, &entry.path, target)?;
+				}
+			}
+		}
+
+		let mut encoder = builder.into_inner()?;
+
+		encoder.flush()?;
+
+		Ok(encoder.finish()?)
+	}
+}
+
+pub fn sha256(data: &[u8]) -> String {
+
+	Sha256::digest(data).iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Path a symlink at `path` points to, relative to the export root, None when it leaves the export
+pub fn resolve_link(path: &str, target: &str) -> Option<String> {
+
+	if target.starts_with('/') {
+		return None;
+	}
+
+	let mut parts: Vec<&str> = path.split('/').collect();
+	parts.pop();
+
+	for part in target.split('/') {
+
+		match part {
+			"" | "." => {},
+			".." => {
+				parts.pop()?;
+			},
+			part => parts.push(part)
+		}
+	}
+
+	Some(parts.join("/"))
+}
+
+/// Stream `git archive --format=tar` of `rev` and hand every regular file and symlink to `add` with its path below `prefix`
+pub fn read_archive<F>(repo: &Path, rev: &str, prefix: &str, mut add: F) -> Result<(), anyhow::Error>
+	where F: FnMut(String, Content) -> Result<(), anyhow::Error> {
+
+	let mut child = Command::new("git")
+		.current_dir(repo)
+		.args(["archive", "--format=tar", rev])
+		.stdout(Stdio::piped())
+		.stderr(Stdio::piped())
+		.spawn()?;
+
+	let stdout = child.stdout.take().unwrap();
+
+	let mut archive = Archive::new(stdout);
+
+	for entry in archive.entries()? {
+
+		let mut entry = entry?;
+
+		let entry_type = entry.header().entry_type();
+
+		if entry_type != EntryType::Regular && entry_type != EntryType::Symlink {
+			continue;
+		}
+
+		let path = entry.path()?.to_string_lossy().to_string();
+
+		let content = if entry_type == EntryType::Symlink {
+
+			let target = entry.link_name()?.unwrap_or_default();
+
+			Content::Symlink(target.to_string_lossy().to_string())
+		}
+		else {
+
+			let mut data = vec![];
+
+			entry.read_to_end(&mut data)?;
+
+			Content::File(data)
+		};
+
+		add(format!("{}{}", prefix, path), content)?;
+	}
+
+	let mut stderr = String::new();
+
+	child.stderr.take().unwrap().read_to_string(&mut stderr)?;
+
+	if !child.wait()?.success() {
+		return Err(Failure::Git.wrap(anyhow!("Could not create git archive: {}", stderr.trim_end())));
+	}
+
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+
+	use super::*;
+
+	#[test]
+	fn resolve_relative_links() {
+
+		assert_eq!(resolve_link("link.txt", "m.txt").as_deref(), Some("m.txt"));
+		assert_eq!(resolve_link("a/b/link", "../c/./d").as_deref(), Some("a/c/d"));
+		assert_eq!(resolve_link("a/link", "../m.txt").as_deref(), Some("m.txt"));
+	}
+
+	#[test]
+	fn resolve_rejects_links_leaving_the_export() {
+
+		assert_eq!(resolve_link("link", "/etc/passwd"), None);
+		assert_eq!(resolve_link("a/link", "../../m.txt"), None);
+	}
+}
@@ -0,0 +1,185 @@
+use std::{collections::HashSet, env, fs, io::Write, path::{Path, PathBuf}, process::{Command, Stdio}, thread};
+use anyhow::anyhow;
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+
+use crate::{cli::Arguments, config::CONFIG_FILE, git};
+
+pub const DEPLOY_IGNORE: &str = ".deployignore";
+
+/// Paths that must not be deployed: `.deployignore` rules plus `--exclude` / `--include` globs
+pub struct Filter {
+	rules: Gitignore
+}
+
+impl Filter {
+
+	pub fn new(args: &Arguments) -> Result<Filter, anyhow::Error> {
+
+		let local_repo = PathBuf::from(&args.local);
+
+		let mut builder = GitignoreBuilder::new(&local_repo);
+
+		builder.add_line(None, DEPLOY_IGNORE)?;
+		builder.add_line(None, CONFIG_FILE)?;
+
+		let ignore_file = local_repo.join(DEPLOY_IGNORE);
+
+		if ignore_file.exists() {
+
+			if let Some(err) = builder.add(&ignore_file) {
+				return Err(anyhow!("Could not read {}: {}", DEPLOY_IGNORE, err));
+			}
+		}
+
+		for pattern in &args.exclude {
+			builder.add_line(None, pattern)?;
+		}
+
+		for pattern in &args.include {
+			builder.add_line(None, &format!("!{}", pattern))?;
+		}
+
+		Ok(Filter {
+			rules: builder.build()?
+		})
+	}
+
+	pub fn is_excluded(&self, path: &str) -> bool {
+
+		self.rules.matched_path_or_any_parents(path, false).is_ignore()
+	}
+}
+
+/// Subset of `paths` carrying the `export-ignore` attribute, checked in the repository that owns each path
+pub fn export_ignored(repo: &Path, rev: &str, paths: &[String]) -> Result<HashSet<String>, anyhow::Error> {
+
+	let mut owners = vec![(String::new(), repo.to_path_buf(), rev.to_string())];
+
+	for module in git::submodules(repo, rev, "")? {
+		owners.push((module.prefix, module.repo, module.commit));
+	}
+
+	owners.sort_by_key(|(prefix, _, _)| std::cmp::Reverse(prefix.len()));
+
+	let mut owned: Vec<Vec<&String>> = vec![vec![]; owners.len()];
+
+	for path in paths {
+
+		let index = owners.iter().position(|(prefix, _, _)| path.starts_with(prefix.as_str())).unwrap();
+
+		owned[index].push(path);
+	}
+
+	// check-attr reads attributes from a revision since git 2.40, older versions only from the working tree or the index
+	let has_source = git::version()? >= (2, 40);
+
+	let index_file = env::temp_dir().join(format!("repo-executor-attributes-{}.index", std::process::id()));
+
+	let mut ignored = HashSet::new();
+
+	for ((prefix, owner, commit), owned) in owners.iter().zip(owned) {
+
+		if owned.is_empty() {
+			continue;
+		}
+
+		// Directories can carry export-ignore too, so every ancestor is checked along with the file
+		let mut candidates: Vec<&str> = vec![];
+
+		for path in &owned {
+
+			let relative = &path[prefix.len()..];
+
+			for (index, _) in relative.match_indices('/') {
+				candidates.push(&relative[..=index]);
+			}
+
+			candidates.push(relative);
+		}
+
+		candidates.sort();
+		candidates.dedup();
+
+		let mut command = Command::new("git");
+
+		command.current_dir(owner);
+
+		if has_source {
+			command.args(["check-attr", &format!("--source={}", commit)]);
+		}
+		else {
+
+			let output = Command::new("git")
+				.current_dir(owner)
+				.env("GIT_INDEX_FILE", &index_file)
+				.args(["read-tree", commit])
+				.output()?;
+
+			if !output.status.success() {
+				return Err(anyhow!("Could not read {} into a temporary index: {}", commit, String::from_utf8_lossy(&output.stderr).trim_end()));
+			}
+
+			command.env("GIT_INDEX_FILE", &index_file).args(["check-attr", "--cached"]);
+		}
+
+		let mut child = command
+			.args(["--stdin", "-z", "export-ignore"])
+			.stdin(Stdio::piped())
+			.stdout(Stdio::piped())
+			.spawn()?;
+
+		let mut input = vec![];
+
+		for candidate in &candidates {
+			input.extend_from_slice(candidate.as_bytes());
+			input.push(0);
+		}
+
+		let mut stdin = child.stdin.take().unwrap();
+
+		// Git answers while it reads, so stdin is written alongside reading stdout or both pipes fill up
+		let output = thread::scope(|scope| {
+
+			let writer = scope.spawn(move || stdin.write_all(&input));
+
+			let output = child.wait_with_output();
+
+			writer.join().unwrap()?;
+
+			output
+		});
+
+		let _ = fs::remove_file(&index_file);
+
+		let output = output?;
+
+		if !output.status.success() {
+			return Err(anyhow!("Could not check export-ignore attributes"));
+		}
+
+		let mut set = HashSet::new();
+
+		// Output is a sequence of <path> NUL <attribute> NUL <value> NUL
+		let fields: Vec<&[u8]> = output.stdout.split(|b| *b == 0).collect();
+
+		for chunk in fields.chunks(3) {
+
+			if chunk.len() == 3 && chunk[2] == b"set" {
+				set.insert(String::from_utf8_lossy(chunk[0]).to_string());
+			}
+		}
+
+		for path in owned {
+
+			let relative = &path[prefix.len()..];
+
+			let is_ignored = set.contains(relative) || relative.match_indices('/').any(|(index, _)| set.contains(&relative[..=index]));
+
+			if is_ignored {
+				ignored.insert(path.clone());
+			}
+		}
+	}
+
+	Ok(ignored)
+}
@@ -0,0 +1,354 @@
+use std::{collections::{HashMap, HashSet}, path::{Path, PathBuf}, process::Command};
+use anyhow::anyhow;
+
+use crate::report::Failure;
+
+pub struct Submodule {
+	pub repo: PathBuf,
+	pub prefix: String,
+	pub commit: String
+}
+
+pub fn run(repo: &Path, args: &[&str]) -> Result<String, anyhow::Error> {
+
+	let output = Command::new("git")
+		.current_dir(repo)
+		.args(args)
+		.output()?;
+
+	if !output.status.success() {
+		return Err(Failure::Git.wrap(anyhow!(String::from_utf8_lossy(&output.stderr).trim_end().to_string())));
+	}
+
+	Ok(String::from_utf8(output.stdout)?)
+}
+
+/// Major and minor version of the git binary
+pub fn version() -> Result<(u32, u32), anyhow::Error> {
+
+	let output = run(Path::new("."), &["version"])?;
+
+	let mut numbers = output.trim_start_matches("git version").trim().split('.').map(|number| number.parse::<u32>());
+
+	match (numbers.next(), numbers.next()) {
+		(Some(Ok(major)), Some(Ok(minor))) => Ok((major, minor)),
+		_ => Err(anyhow!("Could not read the git version from {}", output.trim_end()))
+	}
+}
+
+pub fn head_ref(repo: &Path) -> Result<String, anyhow::Error> {
+
+	let head = run(repo, &["rev-parse", "HEAD"])?;
+
+	Ok(head.trim_end().to_string())
+}
+
+pub fn branch(repo: &Path) -> Result<String, anyhow::Error> {
+
+	let branch = run(repo, &["rev-parse", "--abbrev-ref", "HEAD"])?;
+
+	Ok(branch.trim_end().to_string())
+}
+
+fn gitlinks(repo: &Path, rev: &str) -> Result<Vec<(String, String)>, anyhow::Error> {
+
+	let output = run(repo, &["ls-tree", "-r", rev])?;
+
+	let mut links = vec![];
+
+	for line in output.lines() {
+
+		let Some((info, path)) = line.split_once('\t') else {
+			continue;
+		};
+
+		let parts: Vec<&str> = info.split_whitespace().collect();
+
+		if parts.len() == 3 && parts[0] == "160000" {
+			links.push((path.to_string(), parts[2].to_string()));
+		}
+	}
+
+	Ok(links)
+}
+
+/// All submodules reachable from `rev`, nested ones included, with the path prefix they live under
+pub fn submodules(repo: &Path, rev: &str, prefix: &str) -> Result<Vec<Submodule>, anyhow::Error> {
+
+	let mut modules = vec![];
+
+	for (path, commit) in gitlinks(repo, rev)? {
+
+		let sub_repo = repo.join(&path);
+		let sub_prefix = format!("{}{}/", prefix, path);
+
+		if !has_commit(&sub_repo, &commit) {
+			return Err(Failure::Git.wrap(anyhow!("Submodule {} is not checked out at {}", sub_prefix, commit)));
+		}
+
+		let nested = submodules(&sub_repo, &commit, &sub_prefix)?;
+
+		modules.push(Submodule {
+			repo: sub_repo,
+			prefix: sub_prefix,
+			commit
+		});
+
+		modules.extend(nested);
+	}
+
+	Ok(modules)
+}
+
+fn has_commit(repo: &Path, commit: &str) -> bool {
+
+	repo.exists() && run(repo, &["cat-file", "-e", &format!("{}^{{commit}}", commit)]).is_ok()
+}
+
+const GITLINK_MODE: &str = "160000";
+
+/// One entry of `git diff --raw -z`
+#[derive(Debug, PartialEq)]
+struct RawChange<'a> {
+	old_mode: &'a str,
+	new_mode: &'a str,
+	old_commit: &'a str,
+	new_commit: &'a str,
+	path: &'a str
+}
+
+impl RawChange<'_> {
+
+	fn is_gitlink(&self) -> bool {
+
+		self.new_mode == GITLINK_MODE
+	}
+
+	/// Whether the path was a gitlink before too, so only the commits in between changed
+	fn was_gitlink(&self) -> bool {
+
+		self.old_mode == GITLINK_MODE
+	}
+}
+
+/// Split `git diff --raw -z` output into its entries of ":<old mode> <new mode> <old> <new> <status>" NUL <path> NUL
+fn parse_raw(output: &str) -> Vec<RawChange<'_>> {
+
+	let mut changes = vec![];
+
+	let mut fields = output.split('\0');
+
+	while let (Some(info), Some(path)) = (fields.next(), fields.next()) {
+
+		let parts: Vec<&str> = info.trim_start_matches(':').split_whitespace().collect();
+
+		if parts.len() < 4 {
+			continue;
+		}
+
+		changes.push(RawChange {
+			old_mode: parts[0],
+			new_mode: parts[1],
+			old_commit: parts[2],
+			new_commit: parts[3],
+			path
+		});
+	}
+
+	changes
+}
+
+/// Files added or modified between `from` and `to`, descending into submodules whose gitlink moved
+pub fn changed_files(repo: &Path, from: &str, to: &str, prefix: &str) -> Result<Vec<String>, anyhow::Error> {
+
+	// The raw format carries the modes, which tell gitlinks apart without asking git about every path
+	let output = run(repo, &["diff", "--raw", "-z", "--no-abbrev", "--no-renames", "--diff-filter=d", from, to])?;
+
+	let mut files = vec![];
+
+	for change in parse_raw(&output) {
+
+		if !change.is_gitlink() {
+			files.push(format!("{}{}", prefix, change.path));
+			continue;
+		}
+
+		let sub_repo = repo.join(change.path);
+		let sub_prefix = format!("{}{}/", prefix, change.path);
+
+		if !has_commit(&sub_repo, change.new_commit) {
+			return Err(Failure::Git.wrap(anyhow!("Submodule {} is not checked out at {}", sub_prefix, change.new_commit)));
+		}
+
+		if change.was_gitlink() && has_commit(&sub_repo, change.old_commit) {
+			files.extend(changed_files(&sub_repo, change.old_commit, change.new_commit, &sub_prefix)?);
+		}
+		else {
+			files.extend(all_files(&sub_repo, change.new_commit, &sub_prefix)?);
+		}
+	}
+
+	Ok(files)
+}
+
+/// Every file in the tree of `rev`, descending into submodules
+pub fn all_files(repo: &Path, rev: &str, prefix: &str) -> Result<Vec<String>, anyhow::Error> {
+
+	let output = run(repo, &["ls-tree", "-r", rev])?;
+
+	let mut files = vec![];
+
+	for line in output.lines() {
+
+		let Some((info, path)) = line.split_once('\t') else {
+			continue;
+		};
+
+		let parts: Vec<&str> = info.split_whitespace().collect();
+
+		if parts.len() == 3 && parts[0] == "160000" {
+
+			let sub_repo = repo.join(path);
+			let sub_prefix = format!("{}{}/", prefix, path);
+
+			if !has_commit(&sub_repo, parts[2]) {
+				return Err(Failure::Git.wrap(anyhow!("Submodule {} is not checked out at {}", sub_prefix, parts[2])));
+			}
+
+			files.extend(all_files(&sub_repo, parts[2], &sub_prefix)?);
+		}
+		else {
+			files.push(format!("{}{}", prefix, path));
+		}
+	}
+
+	Ok(files)
+}
+
+/// Files tracked as executable in the tree of `rev`, descending into submodules
+pub fn executables(repo: &Path, rev: &str, prefix: &str) -> Result<HashSet<String>, anyhow::Error> {
+
+	files_with_mode(repo, rev, prefix, "100755")
+}
+
+/// Symlinks in the tree of `rev`, descending into submodules
+pub fn symlinks(repo: &Path, rev: &str, prefix: &str) -> Result<HashSet<String>, anyhow::Error> {
+
+	files_with_mode(repo, rev, prefix, "120000")
+}
+
+fn files_with_mode(repo: &Path, rev: &str, prefix: &str, mode: &str) -> Result<HashSet<String>, anyhow::Error> {
+
+	let output = run(repo, &["ls-tree", "-r", rev])?;
+
+	let mut files = HashSet::new();
+
+	for line in output.lines() {
+
+		let Some((info, path)) = line.split_once('\t') else {
+			continue;
+		};
+
+		let parts: Vec<&str> = info.split_whitespace().collect();
+
+		if parts.len() != 3 {
+			continue;
+		}
+
+		if parts[0] == mode {
+			files.insert(format!("{}{}", prefix, path));
+		}
+		else if parts[0] == GITLINK_MODE {
+
+			let sub_repo = repo.join(path);
+
+			if has_commit(&sub_repo, parts[2]) {
+				files.extend(files_with_mode(&sub_repo, parts[2], &format!("{}{}/", prefix, path), mode)?);
+			}
+		}
+	}
+
+	Ok(files)
+}
+
+/// Time of the last commit up to `rev` touching each file, submodules included
+pub fn commit_times(repo: &Path, rev: &str) -> Result<HashMap<String, i64>, anyhow::Error> {
+
+	let mut times = HashMap::new();
+
+	log_times(repo, rev, "", &mut times)?;
+
+	for module in submodules(repo, rev, "")? {
+		log_times(&module.repo, &module.commit, &module.prefix, &mut times)?;
+	}
+
+	Ok(times)
+}
+
+fn log_times(repo: &Path, rev: &str, prefix: &str, times: &mut HashMap<String, i64>) -> Result<(), anyhow::Error> {
+
+	let output = run(repo, &["-c", "core.quotePath=false", "log", "--format=%x01%ct", "--name-only", rev])?;
+
+	let mut current = 0;
+
+	for line in output.lines() {
+
+		if let Some(time) = line.strip_prefix('\u{1}') {
+			current = time.trim().parse()?;
+		}
+		else if !line.is_empty() {
+			times.entry(format!("{}{}", prefix, line)).or_insert(current);
+		}
+	}
+
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+
+	use super::*;
+
+	const OLD: &str = "1111111111111111111111111111111111111111";
+	const NEW: &str = "2222222222222222222222222222222222222222";
+	const ZERO: &str = "0000000000000000000000000000000000000000";
+
+	#[test]
+	fn parse_raw_modes_and_paths() {
+
+		let output = format!(
+			":100644 100644 {OLD} {NEW} M\0src/a b.php\0:160000 160000 {OLD} {NEW} M\0libs/moved\0:000000 160000 {ZERO} {NEW} A\0libs/added\0:100644 160000 {OLD} {NEW} T\0libs/typechanged\0"
+		);
+
+		let changes = parse_raw(&output);
+
+		let paths: Vec<&str> = changes.iter().map(|change| change.path).collect();
+
+		assert_eq!(paths, ["src/a b.php", "libs/moved", "libs/added", "libs/typechanged"]);
+
+		assert!(!changes[0].is_gitlink());
+
+		assert!(changes[1].is_gitlink() && changes[1].was_gitlink());
+		assert_eq!((changes[1].old_commit, changes[1].new_commit), (OLD, NEW));
+
+		assert!(changes[2].is_gitlink() && !changes[2].was_gitlink());
+		assert!(changes[3].is_gitlink() && !changes[3].was_gitlink());
+	}
+
+	#[test]
+	fn parse_raw_gitlink_replaced_by_file() {
+
+		let output = format!(":160000 100644 {OLD} {NEW} T\0libs/lib\0");
+
+		let changes = parse_raw(&output);
+
+		assert_eq!(changes.len(), 1);
+		assert!(!changes[0].is_gitlink());
+	}
+
+	#[test]
+	fn parse_raw_empty_diff() {
+
+		assert!(parse_raw("").is_empty());
+	}
+}
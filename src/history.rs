@@ -0,0 +1,131 @@
+use std::path::Path;
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+
+use crate::{cli::Arguments, git, info, lock, transport::Transport};
+
+pub const HISTORY_FILE: &str = "deploy-history.jsonl";
+
+/// One deployment, a line of the history file kept in the destination
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct Entry {
+	pub timestamp: String,
+	pub user: String,
+	pub host: String,
+	pub branch: String,
+	pub from: String,
+	pub to: String,
+	pub mode: String,
+	pub uploaded: usize,
+	pub deleted: usize,
+	pub result: String,
+	#[serde(skip_serializing_if = "String::is_empty")]
+	pub error: String
+}
+
+impl Entry {
+
+	pub fn new(args: &Arguments) -> Entry {
+
+		let mode = if args.dist {
+			"dist"
+		}
+		else if args.create {
+			"full"
+		}
+		else if args.manifest {
+			"manifest"
+		}
+		else {
+			"incremental"
+		};
+
+		Entry {
+			timestamp: Local::now().to_rfc3339(),
+			user: lock::local_user(),
+			host: lock::local_host(),
+			mode: mode.to_string(),
+			..Default::default()
+		}
+	}
+}
+
+/// Entries of the history file in `dest`, oldest first, none when there is no file yet
+pub fn load(transport: &mut dyn Transport, dest: &str) -> Vec<Entry> {
+
+	let Ok(data) = transport.get(&format!("{}/{}", dest, HISTORY_FILE)) else {
+		return vec![];
+	};
+
+	String::from_utf8_lossy(&data).lines()
+		.filter_map(|line| serde_json::from_str::<Entry>(line).ok())
+		.collect()
+}
+
+/// Add `entry` to the history file in `dest`, replacing the file as a whole since ftp cannot append reliably
+pub fn append(transport: &mut dyn Transport, dest: &str, entry: &Entry) -> Result<(), anyhow::Error> {
+
+	let path = format!("{}/{}", dest, HISTORY_FILE);
+
+	let mut data = transport.get(&path).unwrap_or_default();
+
+	if !data.is_empty() && !data.ends_with(b"\n") {
+		data.push(b'\n');
+	}
+
+	data.extend(serde_json::to_string(entry)?.as_bytes());
+	data.push(b'\n');
+
+	let temp_path = format!("{}.tmp-{}", path, std::process::id());
+
+	transport.put(&temp_path, &data)?;
+	transport.rename(&temp_path, &path)?;
+
+	Ok(())
+}
+
+fn short(revision: &str) -> &str {
+
+	&revision[..revision.len().min(8)]
+}
+
+/// Print the last `count` entries, newest first, with the subject of every commit deployed
+pub fn print(entries: &[Entry], count: usize, repo: &Path) {
+
+	info!();
+
+	if entries.is_empty() {
+		info!("No deployments recorded yet");
+		return;
+	}
+
+	for entry in entries.iter().rev().take(count) {
+
+		let time = DateTime::parse_from_rfc3339(&entry.timestamp)
+			.map(|time| time.with_timezone(&Local).format("%Y-%m-%d %H:%M").to_string())
+			.unwrap_or_else(|_| entry.timestamp.clone());
+
+		let from = if entry.from.is_empty() { "-" } else { short(&entry.from) };
+
+		info!(
+			"{}  {}@{}  {} -> {}  {}  {} uploaded, {} deleted  {}",
+			time, entry.user, entry.host, from, short(&entry.to), entry.mode, entry.uploaded, entry.deleted, entry.result
+		);
+
+		let subject = git::run(repo, &["log", "-1", "--format=%s", &entry.to])
+			.map(|subject| subject.trim_end().to_string())
+			.unwrap_or_else(|_| String::from("( commit not in the local repository )"));
+
+		info!("    {}", subject);
+
+		if !entry.error.is_empty() {
+			info!("    {}", entry.error);
+		}
+	}
+
+	if entries.len() > count {
+		info!();
+		info!("{} older deployments not shown", entries.len() - count);
+	}
+}
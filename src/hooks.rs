@@ -0,0 +1,155 @@
+use std::{path::PathBuf, process::{Command, Stdio}};
+use anyhow::anyhow;
+
+use crate::{cli::Arguments, info, log, secrets, transport::{self, Transport}};
+
+#[derive(Debug, Clone, Copy)]
+pub enum Stage {
+	BeforeExport,
+	BeforeUpload,
+	AfterUpload,
+	AfterRevision,
+	OnFailure
+}
+
+impl Stage {
+
+	pub fn name(&self) -> &'static str {
+
+		match self {
+			Stage::BeforeExport => "before_export",
+			Stage::BeforeUpload => "before_upload",
+			Stage::AfterUpload => "after_upload",
+			Stage::AfterRevision => "after_revision",
+			Stage::OnFailure => "on_failure"
+		}
+	}
+
+	fn commands<'a>(&self, args: &'a Arguments) -> &'a [String] {
+
+		let hooks = &args.settings.hooks;
+
+		match self {
+			Stage::BeforeExport => &hooks.before_export,
+			Stage::BeforeUpload => &hooks.before_upload,
+			Stage::AfterUpload => &hooks.after_upload,
+			Stage::AfterRevision => &hooks.after_revision,
+			Stage::OnFailure => &hooks.on_failure
+		}
+	}
+}
+
+/// What a hook gets to know about the deployment, exposed as `DEPLOY_*` environment variables
+#[derive(Debug, Clone, Default)]
+pub struct Context {
+	pub revision: String,
+	pub previous: String,
+	pub branch: String,
+	pub host: String,
+	pub destination: String,
+	pub release_path: String,
+	pub error: String
+}
+
+impl Context {
+
+	fn vars(&self) -> Vec<(&'static str, &str)> {
+
+		vec![
+			("DEPLOY_REVISION", &self.revision),
+			("DEPLOY_PREVIOUS", &self.previous),
+			("DEPLOY_BRANCH", &self.branch),
+			("DEPLOY_HOST", &self.host),
+			("DEPLOY_DESTINATION", &self.destination),
+			("DEPLOY_RELEASE_PATH", &self.release_path),
+			("DEPLOY_ERROR", &self.error)
+		]
+	}
+}
+
+/// Command running `cmd` through the shell of the platform
+pub fn shell(cmd: &str) -> Command {
+
+	if cfg!(target_os = "windows") {
+
+		let mut command = Command::new("cmd");
+		command.args(["/C", cmd]);
+		command
+	} else {
+
+		let mut command = Command::new("sh");
+		command.args(["-c", cmd]);
+		command
+	}
+}
+
+/// Run the hooks of `stage` in the local repository
+pub fn run_local(args: &Arguments, stage: Stage, context: &Context) -> Result<(), anyhow::Error> {
+
+	let local_path = PathBuf::from(&args.local);
+
+	for cmd in stage.commands(args) {
+
+		info!();
+		info!("HOOK {}: {}", stage.name(), secrets::mask(cmd));
+		info!();
+
+		let status = shell(cmd)
+			.current_dir(&local_path)
+			.envs(context.vars())
+			.stdout(log::child_output())
+			.stderr(Stdio::inherit())
+			.status()?;
+
+		if !status.success() {
+			return Err(anyhow!("Hook {} `{}` failed with {}", stage.name(), secrets::mask(cmd), status));
+		}
+	}
+
+	Ok(())
+}
+
+/// Fail before anything is uploaded if server side hooks are configured for a transport that cannot run them
+pub fn check_remote(args: &Arguments, transport: &dyn Transport) -> Result<(), anyhow::Error> {
+
+	for stage in [Stage::AfterUpload, Stage::AfterRevision] {
+
+		if !stage.commands(args).is_empty() && !transport.can_exec() {
+			return Err(anyhow!("Hook {} needs to run on the server, which requires ssh", stage.name()));
+		}
+	}
+
+	Ok(())
+}
+
+/// Run the hooks of `stage` on the server, inside the release path
+pub fn run_remote(args: &Arguments, stage: Stage, context: &Context, transport: &mut dyn Transport) -> Result<(), anyhow::Error> {
+
+	let commands = stage.commands(args);
+
+	if commands.is_empty() {
+		return Ok(());
+	}
+
+	if !transport.can_exec() {
+		return Err(anyhow!("Hook {} needs to run on the server, which requires ssh", stage.name()));
+	}
+
+	let exports: Vec<String> = context.vars().iter()
+		.map(|(name, value)| format!("export {}={}", name, transport::quote(value)))
+		.collect();
+
+	for cmd in commands {
+
+		info!();
+		info!("HOOK {} ({}): {}", stage.name(), args.host, secrets::mask(cmd));
+		info!();
+
+		let remote_cmd = format!("cd {} && {} && {}", transport::quote(&context.release_path), exports.join(" && "), cmd);
+
+		transport.exec_streamed(&remote_cmd)
+			.map_err(|err| anyhow!("Hook {} `{}` failed: {}", stage.name(), secrets::mask(cmd), secrets::mask(&err.to_string())))?;
+	}
+
+	Ok(())
+}
@@ -0,0 +1,283 @@
+use std::{collections::BTreeMap, thread, time::{Duration, Instant}};
+use anyhow::anyhow;
+
+use crate::{api::UpToDate, cli::Arguments, config::FailurePolicy, error, info, report::{self, Failure}, secrets};
+
+enum Outcome {
+	Deployed,
+	UpToDate,
+	Failed(String, Option<Failure>),
+	Skipped
+}
+
+/// How the deployment to one host went
+struct Report {
+	host: String,
+	destination: String,
+	outcome: Outcome,
+	elapsed: Duration
+}
+
+/// Whether the selected profile lists hosts to deploy to
+pub fn configured(args: &Arguments) -> bool {
+
+	args.settings.profile(&args.profile).is_some_and(|profile| !profile.hosts.is_empty())
+}
+
+/// Key of the password of `host` in the secrets file
+fn password_key(host: &str) -> String {
+
+	format!("hosts.{}.password", host)
+}
+
+/// One set of arguments per host of the profile, filling in what a host leaves out from the command line
+fn targets(args: &Arguments) -> Vec<Arguments> {
+
+	let Some(profile) = args.settings.profile(&args.profile) else {
+		return vec![];
+	};
+
+	profile.hosts.iter()
+		.map(|host| {
+
+			let mut target = args.clone();
+
+			target.host = host.host.clone();
+
+			if !host.user.is_empty() {
+				target.user = host.user.clone();
+			}
+
+			if let Some(password) = args.vault.values.get(&password_key(&host.host)) {
+				target.password = password.clone();
+			}
+
+			if !host.destination.is_empty() {
+				target.destination = host.destination.clone();
+			}
+
+			if let Some(ftp) = host.ftp {
+				target.new = ftp;
+			}
+
+			target
+		})
+		.collect()
+}
+
+fn deploy_one<F>(target: Arguments, deploy: &F) -> Report
+	where F: Fn(Arguments) -> Result<bool, anyhow::Error> {
+
+	let host = target.host.clone();
+	let destination = target.destination.clone();
+
+	let start = Instant::now();
+
+	let outcome = match deploy(target) {
+		Ok(_) => Outcome::Deployed,
+		Err(err) if err.is::<UpToDate>() => Outcome::UpToDate,
+		Err(err) => {
+
+			let message = format!("{}: {}", host, err);
+
+			error!("Err: {}", secrets::mask(&message));
+
+			report::error(&message);
+
+			Outcome::Failed(secrets::mask(&err.to_string()), Failure::of(&err))
+		}
+	};
+
+	Report {
+		host,
+		destination,
+		outcome,
+		elapsed: start.elapsed()
+	}
+}
+
+/// Order the hosts so those at the same server revisions follow each other, printing the groups
+fn group<R>(targets: Vec<Arguments>, revisions: R) -> Vec<Arguments>
+	where R: Fn(Arguments) -> Result<Vec<String>, anyhow::Error> {
+
+	let mut groups: BTreeMap<String, Vec<Arguments>> = BTreeMap::new();
+
+	for target in targets {
+
+		let revision = match revisions(target.clone()) {
+			Ok(revisions) => revisions.iter()
+				.map(|revision| if revision.is_empty() { "none" } else { revision.as_str() })
+				.collect::<Vec<_>>()
+				.join(", "),
+			Err(_) => String::from("unreachable")
+		};
+
+		groups.entry(revision).or_default().push(target);
+	}
+
+	info!();
+	info!("SERVER REVISIONS");
+
+	for (revision, targets) in &groups {
+
+		let hosts: Vec<String> = targets.iter().map(|target| format!("{} {}", target.host, target.destination)).collect();
+
+		info!("{}: {}", revision, hosts.join(", "));
+	}
+
+	groups.into_values().flatten().collect()
+}
+
+/// Deploy to every host of the profile with `deploy`, one after another or all at once, and print a summary
+///
+/// Unless the whole repository is exported anyway, `revisions` reads what each host has so hosts at the same revision share one export.
+pub fn run<R, F>(args: &Arguments, revisions: R, deploy: F) -> Result<bool, anyhow::Error>
+	where R: Fn(Arguments) -> Result<Vec<String>, anyhow::Error>,
+		F: Fn(Arguments) -> Result<bool, anyhow::Error> + Sync {
+
+	let profile = args.settings.profile(&args.profile).cloned().unwrap_or_default();
+
+	// The config usually sits in the repository, where a password would be committed
+	if let Some(host) = profile.hosts.iter().find(|host| !host.password.is_empty()) {
+		return Err(anyhow!("Remove the password of {} from the config and put it in the secrets file as {}", host.host, password_key(&host.host)));
+	}
+
+	let mut targets = targets(args);
+
+	for target in &targets {
+
+		if target.host.is_empty() || target.destination.is_empty() {
+			return Err(anyhow!("Every host in profile {} needs a host and a destination", args.profile));
+		}
+	}
+
+	if args.command.is_none() && !args.create && !args.manifest {
+		targets = group(targets, revisions);
+	}
+
+	for target in &mut targets {
+		target.parallel_hosts = profile.parallel;
+	}
+
+	let reports = if profile.parallel {
+
+		// Every host starts at once, so there is nothing left for a failure to abort
+		thread::scope(|scope| {
+
+			let handles: Vec<_> = targets.into_iter()
+				.map(|target| {
+
+					let host = target.host.clone();
+					let destination = target.destination.clone();
+
+					(host, destination, scope.spawn(|| deploy_one(target, &deploy)))
+				})
+				.collect();
+
+			handles.into_iter()
+				.map(|(host, destination, handle)| handle.join().unwrap_or(Report {
+					host,
+					destination,
+					outcome: Outcome::Failed(String::from("deployment panicked"), None),
+					elapsed: Duration::ZERO
+				}))
+				.collect::<Vec<_>>()
+		})
+	}
+	else {
+
+		let mut reports = vec![];
+		let mut aborted = false;
+
+		for target in targets {
+
+			if aborted {
+
+				reports.push(Report {
+					host: target.host,
+					destination: target.destination,
+					outcome: Outcome::Skipped,
+					elapsed: Duration::ZERO
+				});
+
+				continue;
+			}
+
+			info!();
+			info!("HOST: {} -> {}", target.host, target.destination);
+
+			let report = deploy_one(target, &deploy);
+
+			aborted = matches!(report.outcome, Outcome::Failed(..)) && profile.on_host_failure == FailurePolicy::Abort;
+
+			reports.push(report);
+		}
+
+		reports
+	};
+
+	print_summary(&reports);
+
+	let current: Vec<String> = reports.iter()
+		.filter(|report| matches!(report.outcome, Outcome::UpToDate))
+		.map(|report| format!("{} {}", report.host, report.destination))
+		.collect();
+
+	if !current.is_empty() {
+		info!();
+		info!("Already current, skipped: {}", current.join(", "));
+	}
+
+	let failed: Vec<Option<Failure>> = reports.iter()
+		.filter_map(|report| match report.outcome {
+			Outcome::Failed(_, failure) => Some(failure),
+			_ => None
+		})
+		.collect();
+
+	if !failed.is_empty() {
+
+		let err = anyhow!("{} of {} hosts failed", failed.len(), reports.len());
+
+		// The exit code follows the first host that failed
+		return Err(match failed[0] {
+			Some(failure) => failure.wrap(err),
+			None => err
+		});
+	}
+
+	Ok(reports.iter().any(|report| matches!(report.outcome, Outcome::Deployed)))
+}
+
+fn print_summary(reports: &[Report]) {
+
+	let rows: Vec<[String; 4]> = reports.iter()
+		.map(|report| {
+
+			let outcome = match &report.outcome {
+				Outcome::Deployed => String::from("deployed"),
+				Outcome::UpToDate => String::from("up to date"),
+				Outcome::Failed(err, _) => format!("failed: {}", err.lines().next().unwrap_or_default()),
+				Outcome::Skipped => String::from("skipped")
+			};
+
+			[report.host.clone(), report.destination.clone(), outcome, format!("{:.1}s", report.elapsed.as_secs_f64())]
+		})
+		.collect();
+
+	let header = [String::from("HOST"), String::from("DESTINATION"), String::from("RESULT"), String::from("TIME")];
+
+	let mut widths = header.clone().map(|column| column.len());
+
+	for row in &rows {
+		for (width, column) in widths.iter_mut().zip(row) {
+			*width = (*width).max(column.len());
+		}
+	}
+
+	info!();
+
+	for row in std::iter::once(&header).chain(&rows) {
+		info!("{:<w0$}  {:<w1$}  {:<w2$}  {:>w3$}", row[0], row[1], row[2], row[3], w0 = widths[0], w1 = widths[1], w2 = widths[2], w3 = widths[3]);
+	}
+}
@@ -0,0 +1,113 @@
+use std::{collections::HashSet, env, fs::{self, OpenOptions}, io::Write, path::PathBuf};
+use serde::{Deserialize, Serialize};
+
+use crate::{cli::Arguments, export, info};
+
+#[derive(Serialize, Deserialize)]
+struct Header {
+	revision: String,
+	destination: String
+}
+
+/// Local record of the files a deployment has already put on the server, so an interrupted run can be resumed
+pub struct Journal {
+	path: PathBuf,
+	file: fs::File,
+	pub destination: String,
+	pub uploaded: HashSet<String>
+}
+
+impl Journal {
+
+	fn path(args: &Arguments) -> PathBuf {
+
+		let key = export::sha256(format!("{}|{}", args.host, args.destination).as_bytes());
+
+		env::temp_dir()
+			.join("repo-executor")
+			.join(format!("journal-{}.txt", &key[..16]))
+	}
+
+	/// Open the journal for deploying `revision` to `destination`, picking up where the last run stopped when resuming it
+	pub fn open(args: &Arguments, revision: &str, destination: &str) -> Result<Journal, anyhow::Error> {
+
+		let path = Journal::path(args);
+
+		if args.resume && path.exists() {
+
+			let content = fs::read_to_string(&path)?;
+
+			let mut lines = content.lines();
+
+			let header = lines.next().and_then(|line| serde_json::from_str::<Header>(line).ok());
+
+			match header {
+				Some(header) if header.revision == revision => {
+
+					let uploaded: HashSet<String> = lines.map(String::from).collect();
+
+					info!();
+					info!("Resuming: {} files already uploaded to {}", uploaded.len(), header.destination);
+
+					let file = OpenOptions::new().append(true).open(&path)?;
+
+					return Ok(Journal {
+						path,
+						file,
+						destination: header.destination,
+						uploaded
+					});
+				},
+				Some(header) => {
+					info!();
+					info!("Journal is for {}, starting over", header.revision);
+				},
+				None => {}
+			}
+		}
+
+		fs::create_dir_all(path.parent().unwrap())?;
+
+		let mut file = OpenOptions::new().write(true).create(true).truncate(true).open(&path)?;
+
+		let header = Header {
+			revision: revision.to_string(),
+			destination: destination.to_string()
+		};
+
+		writeln!(file, "{}", serde_json::to_string(&header)?)?;
+
+		Ok(Journal {
+			path,
+			file,
+			destination: destination.to_string(),
+			uploaded: HashSet::new()
+		})
+	}
+
+	pub fn is_done(&self, path: &str) -> bool {
+
+		self.uploaded.contains(path)
+	}
+
+	pub fn record(&mut self, path: &str) -> Result<(), anyhow::Error> {
+
+		writeln!(self.file, "{}", path)?;
+
+		self.file.flush()?;
+
+		self.uploaded.insert(path.to_string());
+
+		Ok(())
+	}
+
+	/// The deployment is complete, forget about it
+	pub fn finish(self) -> Result<(), anyhow::Error> {
+
+		drop(self.file);
+
+		fs::remove_file(self.path)?;
+
+		Ok(())
+	}
+}
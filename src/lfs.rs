@@ -0,0 +1,218 @@
+use std::{fs, path::{Path, PathBuf}};
+use anyhow::anyhow;
+
+use crate::{debug, export::{self, Content, Tree}, git, info};
+
+const POINTER_VERSION: &str = "version https://git-lfs.github.com/spec/";
+const POINTER_MAX_SIZE: u64 = 1024;
+
+pub struct Pointer {
+	pub oid: String,
+	pub size: u64
+}
+
+impl Pointer {
+
+	pub fn parse(data: &[u8]) -> Option<Pointer> {
+
+		if data.len() as u64 >= POINTER_MAX_SIZE || !data.starts_with(POINTER_VERSION.as_bytes()) {
+			return None;
+		}
+
+		let text = std::str::from_utf8(data).ok()?;
+
+		let mut oid = None;
+		let mut size = None;
+
+		for line in text.lines() {
+
+			if let Some(value) = line.strip_prefix("oid sha256:") {
+				oid = Some(value.trim().to_string());
+			}
+			else if let Some(value) = line.strip_prefix("size ") {
+				size = value.trim().parse::<u64>().ok();
+			}
+		}
+
+		let oid = oid?;
+
+		if oid.len() != 64 || !oid.chars().all(|c| c.is_ascii_hexdigit()) {
+			return None;
+		}
+
+		Some(Pointer {
+			oid,
+			size: size?
+		})
+	}
+}
+
+/// Local LFS object store of a single repository, serving the objects of `rev`
+pub struct Store {
+	repo: PathBuf,
+	rev: String,
+	objects: PathBuf,
+	fetched: bool
+}
+
+impl Store {
+
+	pub fn new(repo: &Path, rev: &str) -> Result<Store, anyhow::Error> {
+
+		let storage = git::run(repo, &["config", "lfs.storage"]).unwrap_or_default();
+		let storage = storage.trim_end();
+
+		let lfs_dir = if storage.is_empty() {
+
+			let common_dir = git::run(repo, &["rev-parse", "--git-common-dir"])?;
+
+			repo.join(common_dir.trim_end()).join("lfs")
+		}
+		else {
+			repo.join(storage)
+		};
+
+		Ok(Store {
+			repo: repo.to_path_buf(),
+			rev: rev.to_string(),
+			objects: lfs_dir.join("objects"),
+			fetched: false
+		})
+	}
+
+	fn object_path(&self, pointer: &Pointer) -> PathBuf {
+
+		self.objects
+			.join(&pointer.oid[0..2])
+			.join(&pointer.oid[2..4])
+			.join(&pointer.oid)
+	}
+
+	/// Content of the object `pointer` refers to, fetching from the remote once if it is not stored locally
+	pub fn load(&mut self, pointer: &Pointer, path: &str) -> Result<Vec<u8>, anyhow::Error> {
+
+		let object_path = self.object_path(pointer);
+
+		if !object_path.exists() && !self.fetched {
+
+			self.fetched = true;
+
+			info!("git lfs fetch origin {}", self.rev);
+
+			// Without a ref only the objects of the checked out revision would be fetched
+			git::run(&self.repo, &["lfs", "fetch", "origin", &self.rev])
+				.map_err(|err| anyhow!("Could not fetch LFS objects: {}", err))?;
+		}
+
+		if !object_path.exists() {
+			return Err(anyhow!("LFS object {} for {} is missing", pointer.oid, path));
+		}
+
+		let data = fs::read(&object_path)?;
+
+		if data.len() as u64 != pointer.size {
+			return Err(anyhow!("LFS object {} for {} is {} bytes, expected {}", pointer.oid, path, data.len(), pointer.size));
+		}
+
+		if export::sha256(&data) != pointer.oid.to_ascii_lowercase() {
+			return Err(anyhow!("LFS object {} for {} does not match its hash", pointer.oid, path));
+		}
+
+		Ok(data)
+	}
+}
+
+/// LFS stores of the repository and its submodules, keyed by the path prefix they serve
+pub struct Stores {
+	stores: Vec<(String, Store)>
+}
+
+impl Stores {
+
+	pub fn new(repo: &Path, rev: &str) -> Result<Stores, anyhow::Error> {
+
+		let mut stores = vec![(String::new(), Store::new(repo, rev)?)];
+
+		for module in git::submodules(repo, rev, "")? {
+			stores.push((module.prefix, Store::new(&module.repo, &module.commit)?));
+		}
+
+		// Deepest prefix first so nested submodules win
+		stores.sort_by_key(|(prefix, _)| std::cmp::Reverse(prefix.len()));
+
+		Ok(Stores {
+			stores
+		})
+	}
+
+	pub fn load(&mut self, pointer: &Pointer, path: &str) -> Result<Vec<u8>, anyhow::Error> {
+
+		let (prefix, store) = self.stores.iter_mut()
+			.find(|(prefix, _)| path.starts_with(prefix.as_str()))
+			.unwrap();
+
+		let relative = path[prefix.len()..].to_string();
+
+		store.load(pointer, &relative)
+	}
+}
+
+/// Replace every LFS pointer in `tree` with the object it points to
+pub fn resolve(repo: &Path, rev: &str, tree: &mut Tree) -> Result<usize, anyhow::Error> {
+
+	let mut stores: Option<Stores> = None;
+	let mut count = 0;
+
+	for entry in tree.entries.iter_mut() {
+
+		let Content::File(data) = &mut entry.content else {
+			continue;
+		};
+
+		let Some(pointer) = Pointer::parse(data) else {
+			continue;
+		};
+
+		if stores.is_none() {
+			stores = Some(Stores::new(repo, rev)?);
+		}
+
+		*data = stores.as_mut().unwrap().load(&pointer, &entry.path)?;
+
+		debug!("LFS: {}", entry.path);
+
+		count += 1;
+	}
+
+	Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+
+	use super::*;
+
+	const OID: &str = "4d7a214614ab2935c943f9e0ff69d22eadbb8f32b1258daaa5e2ca24d17e2393";
+
+	#[test]
+	fn parse_pointer() {
+
+		let data = format!("version https://git-lfs.github.com/spec/v1\noid sha256:{}\nsize 12345\n", OID);
+
+		let pointer = Pointer::parse(data.as_bytes()).unwrap();
+
+		assert_eq!(pointer.oid, OID);
+		assert_eq!(pointer.size, 12345);
+	}
+
+	#[test]
+	fn parse_rejects_other_files() {
+
+		assert!(Pointer::parse(b"<?php echo 1;").is_none());
+		assert!(Pointer::parse(b"version https://git-lfs.github.com/spec/v1\noid sha256:abc\nsize 1\n").is_none());
+
+		let data = format!("version https://git-lfs.github.com/spec/v1\noid sha256:{}\n", OID);
+
+		assert!(Pointer::parse(data.as_bytes()).is_none());
+	}
+}
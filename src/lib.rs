@@ -1,2 +1,22 @@
 pub mod api;
-pub mod cli;
\ No newline at end of file
+pub mod backup;
+pub mod build;
+pub mod cli;
+pub mod config;
+pub mod drift;
+pub mod export;
+pub mod filter;
+pub mod git;
+pub mod history;
+pub mod hooks;
+pub mod hosts;
+pub mod journal;
+pub mod lfs;
+pub mod lock;
+pub mod log;
+pub mod manifest;
+pub mod permissions;
+pub mod report;
+pub mod secrets;
+pub mod template;
+pub mod transport;
\ No newline at end of file
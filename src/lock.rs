@@ -0,0 +1,134 @@
+use std::{env, process::Command};
+use anyhow::anyhow;
+use chrono::{Local, TimeZone};
+use serde::{Deserialize, Serialize};
+
+use crate::{cli::Arguments, debug, info, transport::Transport};
+
+pub const LOCK_FILE: &str = ".deploy.lock";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct LockInfo {
+	pub user: String,
+	pub host: String,
+	pub pid: u32,
+	pub timestamp: i64,
+	token: String
+}
+
+impl LockInfo {
+
+	fn new() -> LockInfo {
+
+		let now = Local::now();
+
+		LockInfo {
+			user: local_user(),
+			host: local_host(),
+			pid: std::process::id(),
+			timestamp: now.timestamp(),
+			token: format!("{}-{}", std::process::id(), now.timestamp_nanos_opt().unwrap_or_default())
+		}
+	}
+
+	fn since(&self) -> String {
+
+		match Local.timestamp_opt(self.timestamp, 0).single() {
+			Some(time) => time.format("%Y-%m-%d %H:%M:%S").to_string(),
+			None => self.timestamp.to_string()
+		}
+	}
+}
+
+pub fn local_user() -> String {
+
+	env::var("USER")
+		.or_else(|_| env::var("USERNAME"))
+		.unwrap_or_else(|_| String::from("unknown"))
+}
+
+pub fn local_host() -> String {
+
+	let output = Command::new("hostname").output();
+
+	match output {
+		Ok(output) if output.status.success() => String::from_utf8_lossy(&output.stdout).trim().to_string(),
+		_ => env::var("COMPUTERNAME").unwrap_or_else(|_| String::from("unknown"))
+	}
+}
+
+/// Lock file held in the destination for the duration of a deployment
+pub struct Lock {
+	path: String,
+	info: LockInfo
+}
+
+impl Lock {
+
+	pub fn acquire(args: &Arguments, dest: &str, transport: &mut dyn Transport) -> Result<Lock, anyhow::Error> {
+
+		let path = format!("{}/{}", dest, LOCK_FILE);
+
+		if args.force_unlock {
+
+			if transport.get(&path).is_ok() {
+
+				transport.remove(&path)?;
+
+				info!("Removed lock {}", path);
+			}
+		}
+		else if let Some(holder) = read(transport, &path) {
+
+			let age = Local::now().timestamp() - holder.timestamp;
+
+			if age < args.lock_timeout as i64 * 60 {
+
+				return Err(anyhow!(
+					"{} is locked by {}@{} (pid {}) since {}, use --force-unlock to override",
+					dest, holder.user, holder.host, holder.pid, holder.since()
+				));
+			}
+
+			info!("Taking over stale lock held by {}@{} since {}", holder.user, holder.host, holder.since());
+		}
+
+		let info = LockInfo::new();
+
+		transport.put(&path, serde_json::to_string(&info)?.as_bytes())?;
+
+		// Someone else may have written their lock at the same time, whoever is in the file wins
+		match read(transport, &path) {
+			Some(holder) if holder == info => {},
+			Some(holder) => {
+				return Err(anyhow!("{} was locked by {}@{} (pid {}) at the same time", dest, holder.user, holder.host, holder.pid));
+			},
+			None => {
+				return Err(anyhow!("Could not verify lock {}", path));
+			}
+		}
+
+		debug!("Locked {}", path);
+
+		Ok(Lock {
+			path,
+			info
+		})
+	}
+
+	pub fn release(self, transport: &mut dyn Transport) -> Result<(), anyhow::Error> {
+
+		if read(transport, &self.path) == Some(self.info) {
+			transport.remove(&self.path)?;
+		}
+
+		Ok(())
+	}
+}
+
+fn read(transport: &mut dyn Transport, path: &str) -> Option<LockInfo> {
+
+	let data = transport.get(path).ok()?;
+
+	serde_json::from_slice::<LockInfo>(&data).ok()
+}
@@ -0,0 +1,159 @@
+use std::{fs::{self, File}, io::{self, Write}, path::PathBuf, process::Stdio, sync::{Mutex, OnceLock}};
+use chrono::Local;
+
+use crate::{cli::{Arguments, Output}, secrets};
+
+static LOGGER: OnceLock<Logger> = OnceLock::new();
+
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+pub enum Level {
+	Error,
+	Warn,
+	Info,
+	Debug
+}
+
+impl Level {
+
+	fn name(&self) -> &'static str {
+
+		match self {
+			Level::Error => "ERROR",
+			Level::Warn => "WARN",
+			Level::Info => "INFO",
+			Level::Debug => "DEBUG"
+		}
+	}
+}
+
+/// Where messages go: the console up to `console` level, and every message to the log file if there is one
+struct Logger {
+	console: Level,
+	stderr: bool,
+	file: Option<Mutex<File>>
+}
+
+/// Set up logging from the arguments, verbose messages reach the console with `--verbose` unless `--log` sends them to a file only, or always with `--stdprint`
+pub fn init(args: &Arguments) -> Result<(), anyhow::Error> {
+
+	let console = if args.stdprint || (args.verbose && args.log.is_none()) {
+		Level::Debug
+	}
+	else {
+		Level::Info
+	};
+
+	let mut path = None;
+
+	let file = match &args.log {
+		Some(dir) => {
+
+			let dir = PathBuf::from(shellexpand::full(dir)?.to_string());
+
+			fs::create_dir_all(&dir)?;
+
+			let file_path = dir.join(format!("deploy-{}.log", Local::now().format("%Y%m%d-%H%M%S")));
+
+			let file = File::create(&file_path)?;
+
+			path = Some(file_path);
+
+			Some(Mutex::new(file))
+		},
+		None => None
+	};
+
+	let _ = LOGGER.set(Logger {
+		console,
+		stderr: args.output == Output::Json,
+		file
+	});
+
+	if let Some(path) = path {
+		write(Level::Info, &format!("Logging to {}", path.to_string_lossy()));
+	}
+
+	Ok(())
+}
+
+/// Log `message` at `level`, with secret values masked, an empty message only spacing out the console
+pub fn write(level: Level, message: &str) {
+
+	let Some(logger) = LOGGER.get() else {
+
+		if level <= Level::Info {
+			println!("{}", secrets::mask(message));
+		}
+
+		return;
+	};
+
+	let message = secrets::mask(message);
+
+	if level <= logger.console {
+
+		if logger.stderr {
+			eprintln!("{}", message);
+		}
+		else {
+			println!("{}", message);
+		}
+	}
+
+	if let Some(file) = &logger.file {
+
+		if message.is_empty() {
+			return;
+		}
+
+		let mut file = file.lock().unwrap();
+
+		for line in message.lines() {
+			let _ = writeln!(file, "{} {:<5} {}", Local::now().format("%Y-%m-%d %H:%M:%S%.3f"), level.name(), line);
+		}
+	}
+}
+
+/// Where progress and remote output go, stderr when stdout carries the JSON report
+pub fn console() -> Box<dyn Write + Send> {
+
+	if LOGGER.get().is_some_and(|logger| logger.stderr) {
+		Box::new(io::stderr())
+	}
+	else {
+		Box::new(io::stdout())
+	}
+}
+
+/// Output for local commands, following `console`
+pub fn child_output() -> Stdio {
+
+	if LOGGER.get().is_some_and(|logger| logger.stderr) {
+		Stdio::from(io::stderr())
+	}
+	else {
+		Stdio::inherit()
+	}
+}
+
+#[macro_export]
+macro_rules! error {
+	($($arg:tt)*) => { $crate::log::write($crate::log::Level::Error, &format!($($arg)*)) };
+}
+
+#[macro_export]
+macro_rules! warn {
+	($($arg:tt)*) => { $crate::log::write($crate::log::Level::Warn, &format!($($arg)*)) };
+}
+
+#[macro_export]
+macro_rules! info {
+	() => { $crate::log::write($crate::log::Level::Info, "") };
+	($($arg:tt)*) => { $crate::log::write($crate::log::Level::Info, &format!($($arg)*)) };
+}
+
+#[macro_export]
+macro_rules! debug {
+	() => { $crate::log::write($crate::log::Level::Debug, "") };
+	($($arg:tt)*) => { $crate::log::write($crate::log::Level::Debug, &format!($($arg)*)) };
+}
@@ -1,60 +1,65 @@
+use std::{process, time::Instant};
 use clap::Parser;
-use repo_executor::{api::{Executor, Export, FtpExport}, cli::Arguments};
+use repo_executor::{api::{self, Executor, Export, FtpExport, HostsExport, UpToDate}, cli::{Arguments, Commands, Output}, error, hosts, info, log, report};
 
+/// Exit codes: 0 deployed or up to date, 1 other errors, 2 connection, 3 authentication, 4 git, 5 upload, 6 verification ( drift found )
 fn main() {
 
 	let args = Arguments::parse();
 
-	if args.new {
-
-		let result = FtpExport::new(args);
-
-		match result {
-
-			Ok(exporter) => {
-	
-				println!();
-				println!("*******************");
-				println!("*                 *");
-				println!("*  Repo executor  *");
-				println!("*                 *");
-				println!("*******************");
-	
-				let result = exporter.execute();
-	
-				if let Err(err) = result {
-					println!("Err: {}", err);
-				}
-			},
-			Err(err) => {
-				println!("Err: {}", err);
-			}
-		}	
+	if let Err(err) = log::init(&args) {
+		error!("Err: {}", err);
+		process::exit(1);
 	}
-	else {
-
-		let result = Export::new(args);
-
-		match result {
-
-			Ok(exporter) => {
-	
-				println!();
-				println!("*******************");
-				println!("*                 *");
-				println!("*  Repo executor  *");
-				println!("*                 *");
-				println!("*******************");
-	
-				let result = exporter.execute();
-	
-				if let Err(err) = result {
-					println!("Err: {}", err);
-				}
-			},
-			Err(err) => {
-				println!("Err: {}", err);
-			}
+
+	let output = args.output;
+	let drift = matches!(args.command, Some(Commands::Drift { .. }));
+
+	let start = Instant::now();
+
+	let result = api::prepare(args).and_then(|args| -> Result<Box<dyn Executor>, anyhow::Error> {
+
+		if hosts::configured(&args) {
+			Ok(Box::new(HostsExport::new(args)))
+		}
+		else if args.new {
+			Ok(Box::new(FtpExport::open(args)?))
+		}
+		else {
+			Ok(Box::new(Export::open(args)?))
+		}
+	});
+
+	let result = match result {
+
+		Ok(exporter) => {
+
+			info!();
+			info!("*******************");
+			info!("*                 *");
+			info!("*  Repo executor  *");
+			info!("*                 *");
+			info!("*******************");
+
+			exporter.execute()
+		},
+		Err(err) => Err(err)
+	};
+
+	match &result {
+		Err(err) if err.is::<UpToDate>() => info!("{}", err),
+		Err(err) => error!("Err: {}", err),
+		Ok(_) => {}
+	}
+
+	let code = report::exit_code(&result, drift);
+
+	if output == Output::Json {
+
+		if let Err(err) = report::print(&result, code, start.elapsed()) {
+			error!("Err: {}", err);
 		}
-	}	
+	}
+
+	process::exit(code);
 }
@@ -0,0 +1,93 @@
+use std::collections::BTreeMap;
+use serde::{Deserialize, Serialize};
+
+use crate::{export::{self, Content, Tree}, transport::Transport};
+
+pub const MANIFEST_FILE: &str = "manifest.json";
+
+/// SHA-256 of every deployed file, kept on the server next to revision.json
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Manifest {
+	#[serde(default)]
+	pub files: BTreeMap<String, String>
+}
+
+impl Manifest {
+
+	/// Hash the files and symlink targets of `tree`, leaving out the files describing the deployment itself
+	pub fn from_tree(tree: &Tree, skip: &[&str]) -> Manifest {
+
+		let files = tree.entries.iter()
+			.filter(|entry| entry.path != MANIFEST_FILE && !skip.contains(&entry.path.as_str()))
+			.filter_map(|entry| match &entry.content {
+				Content::File(data) => Some((entry.path.clone(), export::sha256(data))),
+				Content::Symlink(target) => Some((entry.path.clone(), export::sha256(format!("symlink {}", target).as_bytes()))),
+				Content::Dir => None
+			})
+			.collect();
+
+		Manifest {
+			files
+		}
+	}
+
+	/// Read the manifest in `dest`, None when the server has none yet
+	pub fn load(transport: &mut dyn Transport, dest: &str) -> Option<Manifest> {
+
+		let data = transport.get(&format!("{}/{}", dest, MANIFEST_FILE)).ok()?;
+
+		serde_json::from_slice::<Manifest>(&data).ok()
+	}
+
+	/// Paths whose content differs from `server`, and paths `server` has that are no longer deployed
+	pub fn diff(&self, server: &Manifest) -> (Vec<String>, Vec<String>) {
+
+		let changed = self.files.iter()
+			.filter(|(path, hash)| server.files.get(*path) != Some(hash))
+			.map(|(path, _)| path.clone())
+			.collect();
+
+		let removed = server.files.keys()
+			.filter(|path| !self.files.contains_key(*path))
+			.cloned()
+			.collect();
+
+		(changed, removed)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+
+	use super::*;
+
+	fn manifest(files: &[(&str, &str)]) -> Manifest {
+
+		Manifest {
+			files: files.iter().map(|(path, hash)| (path.to_string(), hash.to_string())).collect()
+		}
+	}
+
+	#[test]
+	fn diff_finds_changed_and_removed() {
+
+		let local = manifest(&[("a", "1"), ("b", "2"), ("c", "3")]);
+		let server = manifest(&[("a", "1"), ("b", "old"), ("d", "4")]);
+
+		let (changed, removed) = local.diff(&server);
+
+		assert_eq!(changed, ["b", "c"]);
+		assert_eq!(removed, ["d"]);
+	}
+
+	#[test]
+	fn diff_of_equal_manifests_is_empty() {
+
+		let local = manifest(&[("a", "1")]);
+
+		let (changed, removed) = local.diff(&manifest(&[("a", "1")]));
+
+		assert!(changed.is_empty());
+		assert!(removed.is_empty());
+	}
+}
@@ -0,0 +1,214 @@
+use std::{collections::BTreeMap, path::PathBuf};
+use anyhow::anyhow;
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+
+use crate::{cli::Arguments, debug, export::{Content, Tree}, transport::Transport, warn};
+
+/// Mode of files when none is configured, so modes left on the server by earlier uploads do not stick
+const FILE_MODE: u32 = 0o644;
+
+/// Modes to give uploaded files and directories, directories keeping the server default unless a mode is configured
+pub struct Modes {
+	file: u32,
+	dir: Option<u32>,
+	overrides: Vec<(Gitignore, u32)>,
+	explicit: bool
+}
+
+impl Modes {
+
+	pub fn new(args: &Arguments) -> Result<Modes, anyhow::Error> {
+
+		let Some(permissions) = &args.settings.permissions else {
+			return Ok(Modes {
+				file: FILE_MODE,
+				dir: None,
+				overrides: vec![],
+				explicit: false
+			});
+		};
+
+		let mut overrides = vec![];
+
+		for mode_override in &permissions.overrides {
+
+			let mut builder = GitignoreBuilder::new(PathBuf::from(&args.local));
+
+			builder.add_line(None, &mode_override.glob)?;
+
+			overrides.push((builder.build()?, parse_mode(&mode_override.mode)?));
+		}
+
+		Ok(Modes {
+			file: if permissions.file.is_empty() { FILE_MODE } else { parse_mode(&permissions.file)? },
+			dir: if permissions.dir.is_empty() { None } else { Some(parse_mode(&permissions.dir)?) },
+			overrides,
+			explicit: true
+		})
+	}
+
+	fn find_override(&self, path: &str, is_dir: bool) -> Option<u32> {
+
+		self.overrides.iter().rev()
+			.find(|(glob, _)| glob.matched_path_or_any_parents(path, is_dir).is_ignore())
+			.map(|(_, mode)| *mode)
+	}
+
+	/// Mode of a file, executables getting an execute bit wherever they are readable
+	pub fn file_mode(&self, path: &str, executable: bool) -> u32 {
+
+		if let Some(mode) = self.find_override(path, false) {
+			return mode;
+		}
+
+		if executable {
+			self.file | (self.file & 0o444) >> 2
+		}
+		else {
+			self.file
+		}
+	}
+
+	pub fn dir_mode(&self, path: &str) -> Option<u32> {
+
+		self.find_override(path, true).or(self.dir)
+	}
+}
+
+fn parse_mode(mode: &str) -> Result<u32, anyhow::Error> {
+
+	let digits = mode.strip_prefix("0o").unwrap_or(mode);
+
+	match u32::from_str_radix(digits, 8) {
+		Ok(value) if value <= 0o7777 => Ok(value),
+		_ => Err(anyhow!("Invalid mode {}, expected octal like 0644", mode))
+	}
+}
+
+/// Fail before anything is uploaded if an owner or group is configured for a transport that cannot set it
+pub fn check(args: &Arguments, transport: &dyn Transport) -> Result<(), anyhow::Error> {
+
+	let Some(permissions) = &args.settings.permissions else {
+		return Ok(());
+	};
+
+	if (!permissions.owner.is_empty() || !permissions.group.is_empty()) && !transport.can_exec() {
+		return Err(anyhow!("Setting owner or group requires ssh"));
+	}
+
+	Ok(())
+}
+
+/// Set the modes, and owner if configured, of everything uploaded from `tree` below `dest`, leaving out `skip`
+pub fn apply(args: &Arguments, tree: &Tree, dest: &str, skip: &[&str], transport: &mut dyn Transport) -> Result<(), anyhow::Error> {
+
+	let modes = Modes::new(args)?;
+
+	let mut groups: BTreeMap<u32, Vec<String>> = BTreeMap::new();
+	let mut paths = vec![];
+
+	for entry in &tree.entries {
+
+		if entry.path.is_empty() || skip.contains(&entry.path.as_str()) {
+			continue;
+		}
+
+		// chmod would follow a symlink to its target
+		let mode = match entry.content {
+			Content::Dir => modes.dir_mode(&entry.path),
+			Content::File(_) => Some(modes.file_mode(&entry.path, entry.executable)),
+			Content::Symlink(_) => continue
+		};
+
+		let path = format!("{}/{}", dest, entry.path);
+
+		if let Some(mode) = mode {
+			groups.entry(mode).or_default().push(path.clone());
+		}
+
+		paths.push(path);
+	}
+
+	for (mode, group) in &groups {
+
+		let group: Vec<&str> = group.iter().map(String::as_str).collect();
+
+		if let Err(err) = transport.chmod(&group, *mode) {
+
+			// Without configured permissions the modes are a nicety some servers do not support
+			if modes.explicit {
+				return Err(err);
+			}
+
+			warn!("Could not set permissions: {}", err);
+
+			break;
+		}
+
+		debug!("CHMOD {:o}: {} paths", mode, group.len());
+	}
+
+	if let Some(permissions) = &args.settings.permissions {
+
+		if !permissions.owner.is_empty() || !permissions.group.is_empty() {
+
+			let paths: Vec<&str> = paths.iter().map(String::as_str).collect();
+
+			transport.chown(&paths, &permissions.owner, &permissions.group)?;
+		}
+	}
+
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+
+	use super::*;
+
+	#[test]
+	fn parse_octal_modes() {
+
+		assert_eq!(parse_mode("644").unwrap(), 0o644);
+		assert_eq!(parse_mode("0755").unwrap(), 0o755);
+		assert_eq!(parse_mode("0o2775").unwrap(), 0o2775);
+	}
+
+	#[test]
+	fn default_modes_reset_files_only() {
+
+		let modes = Modes {
+			file: FILE_MODE,
+			dir: None,
+			overrides: vec![],
+			explicit: false
+		};
+
+		assert_eq!(modes.file_mode("config.php", false), 0o644);
+		assert_eq!(modes.file_mode("bin/run.sh", true), 0o755);
+		assert_eq!(modes.dir_mode("bin"), None);
+	}
+
+	#[test]
+	fn executables_get_execute_where_readable() {
+
+		let modes = Modes {
+			file: 0o640,
+			dir: Some(0o750),
+			overrides: vec![],
+			explicit: true
+		};
+
+		assert_eq!(modes.file_mode("run.sh", true), 0o750);
+		assert_eq!(modes.dir_mode("bin"), Some(0o750));
+	}
+
+	#[test]
+	fn parse_rejects_invalid_modes() {
+
+		assert!(parse_mode("").is_err());
+		assert!(parse_mode("rwx").is_err());
+		assert!(parse_mode("0888").is_err());
+		assert!(parse_mode("17777").is_err());
+	}
+}
@@ -0,0 +1,264 @@
+use std::{fmt, sync::Mutex, time::{Duration, Instant}};
+use serde::Serialize;
+
+use crate::{api::UpToDate, secrets};
+
+static TARGETS: Mutex<Vec<Target>> = Mutex::new(vec![]);
+static ERRORS: Mutex<Vec<String>> = Mutex::new(vec![]);
+
+/// What a run failed on, each with its own exit code
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Failure {
+	Connection,
+	Auth,
+	Git,
+	Upload,
+	Verification
+}
+
+impl Failure {
+
+	pub fn exit_code(self) -> i32 {
+
+		match self {
+			Failure::Connection => 2,
+			Failure::Auth => 3,
+			Failure::Git => 4,
+			Failure::Upload => 5,
+			Failure::Verification => 6
+		}
+	}
+
+	/// Tag `err` with this failure, unless it already has one or only says the server is up to date
+	pub fn wrap(self, err: anyhow::Error) -> anyhow::Error {
+
+		if err.is::<Failed>() || err.is::<UpToDate>() {
+			return err;
+		}
+
+		Failed {
+			failure: self,
+			error: err
+		}.into()
+	}
+
+	/// Tag the error of `result` with this failure
+	pub fn tag<T, E>(self, result: Result<T, E>) -> Result<T, anyhow::Error>
+		where E: Into<anyhow::Error> {
+
+		result.map_err(|err| self.wrap(err.into()))
+	}
+
+	/// The failure `err` was tagged with
+	pub fn of(err: &anyhow::Error) -> Option<Failure> {
+
+		err.downcast_ref::<Failed>().map(|failed| failed.failure)
+	}
+}
+
+/// An error tagged with what failed, displayed as the error itself
+#[derive(Debug)]
+struct Failed {
+	failure: Failure,
+	error: anyhow::Error
+}
+
+impl fmt::Display for Failed {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{}", self.error)
+	}
+}
+
+impl std::error::Error for Failed {
+	fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+		self.error.source()
+	}
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+	#[default]
+	Deployed,
+	UpToDate,
+	Failed,
+	Clean,
+	Drift
+}
+
+/// Outcome of one deployment or drift check, for `--output json`
+#[derive(Serialize, Debug, Default)]
+pub struct Target {
+	pub host: String,
+	pub destination: String,
+	pub previous: String,
+	pub revision: String,
+	pub status: Status,
+	pub uploaded: usize,
+	pub deleted: usize,
+	pub bytes: u64,
+	pub duration: f64,
+	#[serde(skip_serializing_if = "String::is_empty")]
+	pub error: String,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub failure: Option<Failure>,
+	#[serde(skip)]
+	start: Option<Instant>
+}
+
+impl Target {
+
+	pub fn new(host: &str, destination: &str, previous: &str, revision: &str) -> Target {
+
+		Target {
+			host: host.to_string(),
+			destination: destination.to_string(),
+			previous: previous.to_string(),
+			revision: revision.to_string(),
+			start: Some(Instant::now()),
+			..Default::default()
+		}
+	}
+
+	/// Count `files` put on the server, together holding `bytes`
+	pub fn add_upload(&mut self, files: usize, bytes: u64) {
+
+		self.uploaded += files;
+		self.bytes += bytes;
+	}
+
+	/// Record the target with the outcome of `result`
+	pub fn finish(mut self, result: &Result<bool, anyhow::Error>) {
+
+		self.duration = self.start.map(|start| start.elapsed().as_secs_f64()).unwrap_or_default();
+
+		if let Err(err) = result {
+
+			if err.is::<UpToDate>() {
+				self.status = Status::UpToDate;
+				self.uploaded = 0;
+				self.deleted = 0;
+				self.bytes = 0;
+			}
+			else {
+				self.status = Status::Failed;
+				self.error = secrets::mask(&err.to_string());
+				self.failure = Failure::of(err);
+			}
+		}
+
+		TARGETS.lock().unwrap().push(self);
+	}
+}
+
+/// Note an error that happened outside of any recorded target
+pub fn error(message: &str) {
+
+	ERRORS.lock().unwrap().push(secrets::mask(message));
+}
+
+/// Exit code for the result of a run, drift counting as a failed verification
+pub fn exit_code(result: &Result<bool, anyhow::Error>, drift: bool) -> i32 {
+
+	match result {
+		Ok(false) if drift => Failure::Verification.exit_code(),
+		Ok(_) => 0,
+		Err(err) if err.is::<UpToDate>() => 0,
+		Err(err) => Failure::of(err).map(Failure::exit_code).unwrap_or(1)
+	}
+}
+
+#[derive(Serialize)]
+struct Report {
+	exit_code: i32,
+	failure: Option<Failure>,
+	duration: f64,
+	targets: Vec<Target>,
+	errors: Vec<String>
+}
+
+/// Print everything recorded during the run as one line of JSON
+pub fn print(result: &Result<bool, anyhow::Error>, exit_code: i32, duration: Duration) -> Result<(), anyhow::Error> {
+
+	let targets = std::mem::take(&mut *TARGETS.lock().unwrap());
+
+	if let Err(err) = result {
+
+		let message = secrets::mask(&err.to_string());
+
+		if !err.is::<UpToDate>() && !targets.iter().any(|target| target.error == message) {
+			error(&message);
+		}
+	}
+
+	let failure = match result {
+		Err(err) => Failure::of(err),
+		Ok(_) if exit_code == Failure::Verification.exit_code() => Some(Failure::Verification),
+		Ok(_) => None
+	};
+
+	let report = Report {
+		exit_code,
+		failure,
+		duration: duration.as_secs_f64(),
+		targets,
+		errors: std::mem::take(&mut *ERRORS.lock().unwrap())
+	};
+
+	println!("{}", serde_json::to_string(&report)?);
+
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+
+	use super::*;
+	use anyhow::anyhow;
+
+	#[test]
+	fn up_to_date_exits_zero() {
+
+		let result: Result<bool, anyhow::Error> = Err(UpToDate(String::from("abc")).into());
+
+		assert_eq!(exit_code(&result, false), 0);
+		assert_eq!(exit_code(&Ok(true), false), 0);
+	}
+
+	#[test]
+	fn drift_exits_as_verification() {
+
+		assert_eq!(exit_code(&Ok(false), true), 6);
+		assert_eq!(exit_code(&Ok(true), true), 0);
+	}
+
+	#[test]
+	fn tagged_errors_are_not_wrapped_again() {
+
+		let err = Failure::Connection.wrap(anyhow!("refused"));
+		let err = Failure::Upload.wrap(err);
+
+		assert_eq!(Failure::of(&err), Some(Failure::Connection));
+		assert_eq!(err.to_string(), "refused");
+		assert_eq!(exit_code(&Err(err), false), 2);
+	}
+
+	#[test]
+	fn up_to_date_is_never_tagged() {
+
+		let err = Failure::Git.wrap(UpToDate(String::from("abc")).into());
+
+		assert!(err.is::<UpToDate>());
+		assert_eq!(Failure::of(&err), None);
+	}
+
+	#[test]
+	fn untagged_errors_exit_one() {
+
+		let result: Result<bool, anyhow::Error> = Err(anyhow!("something else"));
+
+		assert_eq!(Failure::of(result.as_ref().unwrap_err()), None);
+		assert_eq!(exit_code(&result, false), 1);
+	}
+}
@@ -0,0 +1,103 @@
+use std::{collections::BTreeMap, fmt, fs, path::PathBuf, sync::OnceLock};
+use anyhow::anyhow;
+use serde::Deserialize;
+
+use crate::{cli::Arguments, debug, export::{self, Tree}};
+
+static MASKED: OnceLock<Vec<String>> = OnceLock::new();
+
+/// Values for templates and whole files to create on the server, read from a file only its owner can read
+#[derive(Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct Secrets {
+	pub values: BTreeMap<String, String>,
+	pub files: BTreeMap<String, String>
+}
+
+impl fmt::Debug for Secrets {
+
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+
+		f.debug_struct("Secrets")
+			.field("values", &self.values.keys().collect::<Vec<_>>())
+			.field("files", &self.files.keys().collect::<Vec<_>>())
+			.finish()
+	}
+}
+
+impl Secrets {
+
+	/// Read the secrets file given with `--secrets`, by the selected profile or by the config, if any
+	pub fn load(args: &Arguments) -> Result<Secrets, anyhow::Error> {
+
+		let profile = args.settings.profile(&args.profile).map(|profile| profile.secrets.as_str()).unwrap_or_default();
+
+		let path = [args.secrets.as_str(), profile, args.settings.secrets.as_str()].into_iter()
+			.find(|path| !path.is_empty());
+
+		let Some(path) = path else {
+			return Ok(Secrets::default());
+		};
+
+		let path = PathBuf::from(shellexpand::full(path)?.to_string());
+
+		let metadata = fs::metadata(&path)
+			.map_err(|err| anyhow!("Could not read secrets {}: {}", path.to_string_lossy(), err))?;
+
+		#[cfg(unix)]
+		{
+			use std::os::unix::fs::PermissionsExt;
+
+			if metadata.permissions().mode() & 0o077 != 0 {
+				return Err(anyhow!("Secrets {} must only be accessible by its owner, run chmod 600 on it", path.to_string_lossy()));
+			}
+		}
+
+		#[cfg(not(unix))]
+		let _ = metadata;
+
+		let content = fs::read_to_string(&path)?;
+
+		// The error would quote the offending content
+		let secrets = serde_json::from_str::<Secrets>(&content)
+			.map_err(|err| anyhow!("Invalid secrets {} at line {}", path.to_string_lossy(), err.line()))?;
+
+		let mut masked: Vec<String> = secrets.values.values()
+			.chain(secrets.files.values())
+			.filter(|value| !value.is_empty())
+			.cloned()
+			.collect();
+
+		masked.sort_by_key(|value| std::cmp::Reverse(value.len()));
+
+		let _ = MASKED.set(masked);
+
+		Ok(secrets)
+	}
+}
+
+/// `text` with every secret value replaced by asterisks, for anything printed
+pub fn mask(text: &str) -> String {
+
+	let mut text = text.to_string();
+
+	for value in MASKED.get().into_iter().flatten() {
+		text = text.replace(value.as_str(), "********");
+	}
+
+	text
+}
+
+/// Add the secret files to `tree`, recording the hash of each in `tree.secrets`
+pub fn add_files(args: &Arguments, tree: &mut Tree) {
+
+	for (path, content) in &args.vault.files {
+
+		debug!("Adding secret file: {}", path);
+
+		tree.secrets.insert(path.clone(), export::sha256(content.as_bytes()));
+
+		tree.add_file(path, content.clone().into_bytes());
+		tree.set_secret(path);
+	}
+}
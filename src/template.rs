@@ -0,0 +1,185 @@
+use std::{collections::BTreeMap, env};
+use anyhow::anyhow;
+
+use crate::{cli::Arguments, debug, export::{self, Content, Tree}};
+
+pub const TEMPLATE_EXTENSION: &str = ".tmpl";
+
+/// Values for `{{ name }}` placeholders: secrets win over the selected profile, which wins over the config, then the environment
+pub struct Variables<'a> {
+	values: BTreeMap<String, String>,
+	secrets: &'a BTreeMap<String, String>
+}
+
+impl<'a> Variables<'a> {
+
+	pub fn new(args: &'a Arguments) -> Variables<'a> {
+
+		let mut values = args.settings.variables.clone();
+
+		if let Some(profile) = args.settings.profile(&args.profile) {
+			values.extend(profile.variables.clone());
+		}
+
+		Variables {
+			values,
+			secrets: &args.vault.values
+		}
+	}
+
+	/// Value of `name` and whether it is a secret
+	fn get(&self, name: &str) -> Option<(String, bool)> {
+
+		if let Some(value) = self.secrets.get(name) {
+			return Some((value.clone(), true));
+		}
+
+		self.values.get(name).cloned()
+			.or_else(|| env::var(name).ok())
+			.map(|value| (value, false))
+	}
+}
+
+/// Path a template renders to, None for other files
+pub fn output_path(path: &str) -> Option<&str> {
+
+	path.strip_suffix(TEMPLATE_EXTENSION).filter(|output| !output.is_empty() && !output.ends_with('/'))
+}
+
+/// Replace the `{{ name }}` placeholders of the template at `path`, failing on any variable that is not defined, and tell whether a secret went in
+pub fn render(path: &str, source: &str, variables: &Variables) -> Result<(String, bool), anyhow::Error> {
+
+	let mut output = String::with_capacity(source.len());
+	let mut rest = source;
+	let mut secret = false;
+
+	while let Some(start) = rest.find("{{") {
+
+		let Some(end) = rest[start..].find("}}") else {
+			break;
+		};
+
+		let name = rest[start + 2..start + end].trim();
+
+		let Some((value, is_secret)) = variables.get(name) else {
+			return Err(anyhow!("Template {} uses undefined variable {}", path, name));
+		};
+
+		secret |= is_secret;
+
+		output.push_str(&rest[..start]);
+		output.push_str(&value);
+
+		rest = &rest[start + end + 2..];
+	}
+
+	output.push_str(rest);
+
+	Ok((output, secret))
+}
+
+/// Swap every template in `tree` for its rendered output, recording the hash of each in `tree.templates`
+pub fn render_tree(args: &Arguments, tree: &mut Tree) -> Result<(), anyhow::Error> {
+
+	let templates: Vec<String> = tree.files()
+		.filter(|(path, _)| output_path(path).is_some() && args.source_path(path).is_some())
+		.map(|(path, _)| path.to_string())
+		.collect();
+
+	if templates.is_empty() {
+		return Ok(());
+	}
+
+	let variables = Variables::new(args);
+
+	for path in templates {
+
+		let Some(entry) = tree.remove(&path) else {
+			continue;
+		};
+
+		let Content::File(data) = entry.content else {
+			continue;
+		};
+
+		let source = String::from_utf8(data).map_err(|_| anyhow!("Template {} is not valid UTF-8", path))?;
+
+		let (rendered, secret) = render(&path, &source, &variables)?;
+
+		let rendered = rendered.into_bytes();
+
+		let output = output_path(&path).unwrap();
+
+		debug!("Rendering template: {} -> {}", path, output);
+
+		tree.templates.insert(output.to_string(), export::sha256(&rendered));
+
+		tree.add_file(output, rendered);
+
+		if entry.executable {
+			tree.set_executable(output);
+		}
+
+		if secret {
+			tree.set_secret(output);
+		}
+	}
+
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+
+	use super::*;
+
+	fn variables(secrets: &BTreeMap<String, String>) -> Variables<'_> {
+
+		Variables {
+			values: BTreeMap::from([(String::from("host"), String::from("example.com"))]),
+			secrets
+		}
+	}
+
+	#[test]
+	fn render_replaces_placeholders() {
+
+		let secrets = BTreeMap::new();
+
+		let (output, secret) = render("a.tmpl", "url: {{host}}/{{ host }}", &variables(&secrets)).unwrap();
+
+		assert_eq!(output, "url: example.com/example.com");
+		assert!(!secret);
+	}
+
+	#[test]
+	fn render_flags_secrets() {
+
+		let secrets = BTreeMap::from([(String::from("password"), String::from("hunter2"))]);
+
+		let (output, secret) = render("a.tmpl", "{{ password }}", &variables(&secrets)).unwrap();
+
+		assert_eq!(output, "hunter2");
+		assert!(secret);
+	}
+
+	#[test]
+	fn render_keeps_unterminated_placeholder() {
+
+		let secrets = BTreeMap::new();
+
+		let (output, _) = render("a.tmpl", "{{ host }} {{ host", &variables(&secrets)).unwrap();
+
+		assert_eq!(output, "example.com {{ host");
+	}
+
+	#[test]
+	fn render_fails_on_undefined_variable() {
+
+		let secrets = BTreeMap::new();
+
+		let err = render("a.tmpl", "{{ repo_executor_undefined }}", &variables(&secrets)).unwrap_err();
+
+		assert!(err.to_string().contains("repo_executor_undefined"));
+	}
+}
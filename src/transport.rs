@@ -0,0 +1,514 @@
+use std::{io::{Cursor, Read, Write}, net::TcpStream};
+use anyhow::anyhow;
+use ftp::FtpStream;
+use ssh2::{ExtendedData, FileStat, Session};
+
+use crate::{backup::BACKUP_DIR, cli::Arguments, debug, log, report::Failure};
+
+/// Opens a fresh connection for an upload worker
+pub type Connect<'a> = dyn Fn() -> Result<Box<dyn Transport + Send>, anyhow::Error> + Sync + 'a;
+
+/// File found on the server, with whatever the transport can tell about its content
+pub struct RemoteFile {
+	pub path: String,
+	pub size: Option<u64>,
+	pub hash: Option<String>
+}
+
+/// Remote side of a deployment
+pub trait Transport {
+	fn mkdir(&mut self, path: &str) -> Result<(), anyhow::Error>;
+	fn put(&mut self, path: &str, data: &[u8]) -> Result<(), anyhow::Error>;
+
+	/// Move `from` over `to`, replacing it
+	fn rename(&mut self, from: &str, to: &str) -> Result<(), anyhow::Error>;
+
+	fn get(&mut self, path: &str) -> Result<Vec<u8>, anyhow::Error>;
+	fn remove(&mut self, path: &str) -> Result<(), anyhow::Error>;
+
+	/// Every file below `dir` outside of the backups, with paths relative to it
+	fn list_files(&mut self, dir: &str) -> Result<Vec<RemoteFile>, anyhow::Error>;
+
+	fn chmod(&mut self, paths: &[&str], mode: u32) -> Result<(), anyhow::Error>;
+
+	/// Set the modification time of each path, given as a unix timestamp
+	fn set_mtimes(&mut self, times: &[(&str, i64)]) -> Result<(), anyhow::Error>;
+
+	/// Create a symlink at `path` pointing to `target`, replacing what is there
+	fn symlink(&mut self, target: &str, _path: &str) -> Result<(), anyhow::Error> {
+		Err(anyhow!("Cannot link to {}, symlinks require ssh", target))
+	}
+
+	/// Give `paths` to `owner` and/or `group`, whichever is not empty
+	fn chown(&mut self, _paths: &[&str], _owner: &str, _group: &str) -> Result<(), anyhow::Error> {
+		Err(anyhow!("Changing owner or group requires ssh"))
+	}
+
+	fn can_exec(&self) -> bool {
+		false
+	}
+
+	/// Run a shell command on the server and return its stdout
+	fn exec(&mut self, cmd: &str) -> Result<String, anyhow::Error> {
+		Err(anyhow!("Cannot run `{}`, remote commands require ssh", cmd))
+	}
+
+	/// Run a shell command on the server, passing its output through to stdout as it arrives
+	fn exec_streamed(&mut self, cmd: &str) -> Result<(), anyhow::Error> {
+		Err(anyhow!("Cannot run `{}`, remote commands require ssh", cmd))
+	}
+}
+
+/// Quote `value` for a POSIX shell
+pub fn quote(value: &str) -> String {
+
+	format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+pub fn ssh_session(args: &Arguments) -> Result<Session, anyhow::Error> {
+
+	let connection_str = format!("{}:{}", args.host, "22");
+
+	let tcp = Failure::Connection.tag(TcpStream::connect(connection_str))?;
+
+	let mut session = Session::new()?;
+	session.set_compress(true);
+	session.set_tcp_stream(tcp);
+
+	Failure::Connection.tag(session.handshake())?;
+	Failure::Auth.tag(session.userauth_password(&args.user, &args.password))?;
+
+	Ok(session)
+}
+
+pub struct SshTransport {
+	session: Session
+}
+
+impl SshTransport {
+
+	pub fn connect(args: &Arguments) -> Result<Self, anyhow::Error> {
+
+		Ok(Self::new(ssh_session(args)?))
+	}
+
+	pub fn new(session: Session) -> Self {
+
+		Self {
+			session
+		}
+	}
+}
+
+impl Transport for SshTransport {
+
+	fn mkdir(&mut self, path: &str) -> Result<(), anyhow::Error> {
+
+		self.exec(&format!("cd / && mkdir -p {}", quote(path)))?;
+
+		Ok(())
+	}
+
+	fn put(&mut self, path: &str, data: &[u8]) -> Result<(), anyhow::Error> {
+
+		let mut scp = self.session.scp_send(path.as_ref(), 0o644, data.len() as u64, None)?;
+
+		scp.write_all(data)?;
+
+		scp.send_eof()?;
+		scp.wait_eof()?;
+		scp.close()?;
+		scp.wait_close()?;
+
+		debug!("SCP {}: {} bytes", path, data.len());
+
+		Ok(())
+	}
+
+	fn rename(&mut self, from: &str, to: &str) -> Result<(), anyhow::Error> {
+
+		self.exec(&format!("mv -f {} {}", quote(from), quote(to)))?;
+
+		Ok(())
+	}
+
+	fn get(&mut self, path: &str) -> Result<Vec<u8>, anyhow::Error> {
+
+		let (mut remote_file, _) = self.session.scp_recv(path.as_ref())?;
+
+		let mut content = vec![];
+		remote_file.read_to_end(&mut content)?;
+
+		remote_file.send_eof()?;
+		remote_file.wait_eof()?;
+		remote_file.close()?;
+		remote_file.wait_close()?;
+
+		Ok(content)
+	}
+
+	fn remove(&mut self, path: &str) -> Result<(), anyhow::Error> {
+
+		self.exec(&format!("rm -f {}", quote(path)))?;
+
+		Ok(())
+	}
+
+	fn list_files(&mut self, dir: &str) -> Result<Vec<RemoteFile>, anyhow::Error> {
+
+		// Backups grow with every deployment and are never compared, so find does not descend into them
+		let output = self.exec(&format!("cd {} && find . -path ./{} -prune -o -type f -exec sha256sum {{}} +", quote(dir), BACKUP_DIR))?;
+
+		let mut files = vec![];
+
+		for line in output.lines() {
+
+			let Some((hash, path)) = line.split_once("  ") else {
+				continue;
+			};
+
+			files.push(RemoteFile {
+				path: path.strip_prefix("./").unwrap_or(path).to_string(),
+				size: None,
+				hash: Some(hash.trim_start_matches('\\').to_string())
+			});
+		}
+
+		Ok(files)
+	}
+
+	fn chmod(&mut self, paths: &[&str], mode: u32) -> Result<(), anyhow::Error> {
+
+		for chunk in paths.chunks(200) {
+
+			let files: Vec<String> = chunk.iter().map(|path| quote(path)).collect();
+
+			self.exec(&format!("chmod {:o} {}", mode, files.join(" ")))?;
+		}
+
+		Ok(())
+	}
+
+	fn set_mtimes(&mut self, times: &[(&str, i64)]) -> Result<(), anyhow::Error> {
+
+		let sftp = self.session.sftp()?;
+
+		for (path, time) in times {
+
+			let stat = FileStat {
+				size: None,
+				uid: None,
+				gid: None,
+				perm: None,
+				atime: Some(*time as u64),
+				mtime: Some(*time as u64)
+			};
+
+			sftp.setstat(path.as_ref(), stat)?;
+		}
+
+		Ok(())
+	}
+
+	fn symlink(&mut self, target: &str, path: &str) -> Result<(), anyhow::Error> {
+
+		self.exec(&format!("ln -sfn {} {}", quote(target), quote(path)))?;
+
+		Ok(())
+	}
+
+	fn chown(&mut self, paths: &[&str], owner: &str, group: &str) -> Result<(), anyhow::Error> {
+
+		let cmd = if owner.is_empty() {
+			format!("chgrp {}", quote(group))
+		}
+		else if group.is_empty() {
+			format!("chown {}", quote(owner))
+		}
+		else {
+			format!("chown {}", quote(&format!("{}:{}", owner, group)))
+		};
+
+		for chunk in paths.chunks(200) {
+
+			let files: Vec<String> = chunk.iter().map(|path| quote(path)).collect();
+
+			self.exec(&format!("{} {}", cmd, files.join(" ")))?;
+		}
+
+		Ok(())
+	}
+
+	fn can_exec(&self) -> bool {
+		true
+	}
+
+	fn exec(&mut self, cmd: &str) -> Result<String, anyhow::Error> {
+
+		debug!("SSH: {}", cmd);
+
+		let mut channel = self.session.channel_session()?;
+
+		channel.exec(cmd)?;
+
+		let mut output = String::new();
+		channel.read_to_string(&mut output)?;
+
+		let mut stderr = String::new();
+		channel.stderr().read_to_string(&mut stderr)?;
+
+		channel.wait_close()?;
+
+		let status = channel.exit_status()?;
+
+		if status != 0 {
+			return Err(anyhow!("`{}` exited with {}: {}", cmd, status, stderr.trim_end()));
+		}
+
+		Ok(output)
+	}
+
+	fn exec_streamed(&mut self, cmd: &str) -> Result<(), anyhow::Error> {
+
+		debug!("SSH: {}", cmd);
+
+		let mut channel = self.session.channel_session()?;
+
+		channel.handle_extended_data(ExtendedData::Merge)?;
+		channel.exec(cmd)?;
+
+		let mut stdout = log::console();
+		let mut buffer = [0; 4096];
+
+		loop {
+
+			let len = channel.read(&mut buffer)?;
+
+			if len == 0 {
+				break;
+			}
+
+			stdout.write_all(&buffer[..len])?;
+			stdout.flush()?;
+		}
+
+		channel.wait_close()?;
+
+		let status = channel.exit_status()?;
+
+		if status != 0 {
+			return Err(anyhow!("exited with {}", status));
+		}
+
+		Ok(())
+	}
+}
+
+pub struct FtpTransport {
+	stream: FtpStream
+}
+
+impl FtpTransport {
+
+	pub fn connect(args: &Arguments) -> Result<Self, anyhow::Error> {
+
+		let connection_str = format!("{}:{}", args.host, "21");
+
+		let mut stream = Failure::Connection.tag(FtpStream::connect(connection_str))?;
+
+		Failure::Auth.tag(stream.login(&args.user, &args.password))?;
+
+		Ok(Self {
+			stream
+		})
+	}
+
+	/// Send a command the ftp crate has no call for, expecting one of `codes` back
+	fn raw(&mut self, command: &str, codes: &[u32]) -> Result<(), anyhow::Error> {
+
+		debug!("FTP {}", command);
+
+		self.stream.get_ref().write_all(format!("{}\r\n", command).as_bytes())?;
+
+		self.stream.read_response_in(codes)?;
+
+		Ok(())
+	}
+}
+
+impl Transport for FtpTransport {
+
+	fn mkdir(&mut self, path: &str) -> Result<(), anyhow::Error> {
+
+		debug!("FTP MKD {}", path);
+
+		self.stream.mkdir(path)?;
+
+		Ok(())
+	}
+
+	fn put(&mut self, path: &str, data: &[u8]) -> Result<(), anyhow::Error> {
+
+		self.stream.put(path, &mut Cursor::new(data))?;
+
+		debug!("FTP STOR {}: {} bytes", path, data.len());
+
+		Ok(())
+	}
+
+	fn rename(&mut self, from: &str, to: &str) -> Result<(), anyhow::Error> {
+
+		debug!("FTP RNFR {} RNTO {}", from, to);
+
+		if self.stream.rename(from, to).is_err() {
+
+			// Some servers refuse to rename over an existing file
+			let _ = self.stream.rm(to);
+
+			self.stream.rename(from, to)?;
+		}
+
+		Ok(())
+	}
+
+	fn get(&mut self, path: &str) -> Result<Vec<u8>, anyhow::Error> {
+
+		let content = self.stream.simple_retr(path)?;
+
+		Ok(content.into_inner())
+	}
+
+	fn remove(&mut self, path: &str) -> Result<(), anyhow::Error> {
+
+		debug!("FTP DELE {}", path);
+
+		self.stream.rm(path)?;
+
+		Ok(())
+	}
+
+	fn list_files(&mut self, dir: &str) -> Result<Vec<RemoteFile>, anyhow::Error> {
+
+		let mut files = vec![];
+		let mut dirs = vec![String::new()];
+
+		while let Some(sub) = dirs.pop() {
+
+			let path = if sub.is_empty() {
+				dir.to_string()
+			}
+			else {
+				format!("{}/{}", dir, sub)
+			};
+
+			for line in self.stream.list(Some(&path))? {
+
+				let Some((kind, size, name)) = parse_list_line(&line) else {
+					continue;
+				};
+
+				if name == "." || name == ".." {
+					continue;
+				}
+
+				let relative = if sub.is_empty() {
+					name.to_string()
+				}
+				else {
+					format!("{}/{}", sub, name)
+				};
+
+				match kind {
+					'd' if relative == BACKUP_DIR => {},
+					'd' => dirs.push(relative),
+					'-' => files.push(RemoteFile { path: relative, size: Some(size), hash: None }),
+					_ => {}
+				}
+			}
+		}
+
+		Ok(files)
+	}
+
+	fn chmod(&mut self, paths: &[&str], mode: u32) -> Result<(), anyhow::Error> {
+
+		for path in paths {
+			self.raw(&format!("SITE CHMOD {:o} {}", mode, path), &[200, 250])?;
+		}
+
+		Ok(())
+	}
+
+	fn set_mtimes(&mut self, times: &[(&str, i64)]) -> Result<(), anyhow::Error> {
+
+		for (path, time) in times {
+
+			let Some(time) = chrono::DateTime::from_timestamp(*time, 0) else {
+				return Err(anyhow!("Invalid time {} for {}", time, path));
+			};
+
+			self.raw(&format!("MFMT {} {}", time.format("%Y%m%d%H%M%S"), path), &[213])?;
+		}
+
+		Ok(())
+	}
+}
+
+/// Split a unix style LIST line into its type, size and name
+fn parse_list_line(line: &str) -> Option<(char, u64, &str)> {
+
+	let mut rest = line.trim_end();
+	let mut fields = vec![];
+
+	for _ in 0..8 {
+
+		rest = rest.trim_start();
+
+		let end = rest.find(char::is_whitespace)?;
+
+		fields.push(&rest[..end]);
+		rest = &rest[end..];
+	}
+
+	let kind = fields[0].chars().next()?;
+	let size = fields[4].parse().ok()?;
+
+	Some((kind, size, rest.trim_start()))
+}
+
+impl Drop for FtpTransport {
+	fn drop(&mut self) {
+		let _ = self.stream.quit();
+	}
+}
+
+#[cfg(test)]
+mod tests {
+
+	use super::*;
+
+	#[test]
+	fn parse_file_line() {
+
+		assert_eq!(
+			parse_list_line("-rw-r--r--    1 www      www          1234 Jan 01 12:00 index.php"),
+			Some(('-', 1234, "index.php"))
+		);
+	}
+
+	#[test]
+	fn parse_name_with_spaces() {
+
+		assert_eq!(
+			parse_list_line("drwxr-xr-x 2 www www 4096 Mar  3  2023 my  dir\r"),
+			Some(('d', 4096, "my  dir"))
+		);
+	}
+
+	#[test]
+	fn parse_rejects_short_lines() {
+
+		assert_eq!(parse_list_line("total 12"), None);
+		assert_eq!(parse_list_line("-rw-r--r-- 1 www www size Jan 01 12:00 a"), None);
+	}
+}